@@ -0,0 +1,405 @@
+use async_trait::async_trait;
+use ethers::{
+    providers::Middleware,
+    types::{
+        AccountDiff, AccountState, BlockId, BlockNumber, Bytes, ChangedType, Diff, DiffMode,
+        GethDebugBuiltInTracerConfig, GethDebugBuiltInTracerType, GethDebugTracerConfig,
+        GethDebugTracerType, GethDebugTracingCallOptions, GethDebugTracingOptions, GethTrace,
+        GethTraceFrame, PreStateConfig, PreStateFrame, StateDiff, TraceType, Transaction, H256,
+    },
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+use crate::config::BackendKind;
+use crate::error::WatcherError;
+use crate::metrics::Metrics;
+use crate::simulation::RevmBackend;
+
+// Source of state diffs for the pool-matching logic in trace.rs
+#[async_trait]
+pub trait TraceBackend: Send + Sync {
+    // State diff of a (pending) transaction executed on top of `block`
+    async fn trace_call(
+        &self,
+        tx: &Transaction,
+        block: BlockNumber,
+    ) -> Result<StateDiff, WatcherError>;
+
+    // State diff of the last of `txs`, executed in order on top of `block`
+    async fn trace_call_many(
+        &self,
+        _txs: &[Transaction],
+        _block: BlockNumber,
+    ) -> Result<StateDiff, WatcherError> {
+        Err(WatcherError::TraceUnsupported(String::from(
+            "tracing a sequence of transactions is not supported by this backend",
+        )))
+    }
+
+    // State diff of a mined transaction, replayed in its block
+    async fn trace_replay(&self, tx_hash: H256) -> Result<StateDiff, WatcherError>;
+
+    // State diffs of every transaction mined in `block`, in one request
+    async fn trace_replay_block(
+        &self,
+        _block: u64,
+    ) -> Result<Vec<(H256, StateDiff)>, WatcherError> {
+        Err(WatcherError::TraceUnsupported(String::from(
+            "replaying a whole block is not supported by this backend",
+        )))
+    }
+}
+
+pub fn new_backend<M: Middleware + 'static>(
+    kind: BackendKind,
+    provider: Arc<M>,
+    metrics: Arc<Metrics>,
+) -> Arc<dyn TraceBackend>
+where
+    M::Error: 'static,
+{
+    match kind {
+        BackendKind::Parity => Arc::new(ParityBackend::new(provider)),
+        BackendKind::Geth => Arc::new(GethBackend::new(provider)),
+        BackendKind::Revm => Arc::new(RevmBackend::new(provider, metrics)),
+    }
+}
+
+// Parity/Erigon: trace_call, trace_replayTransaction and trace_replayBlockTransactions
+// with TraceType::StateDiff
+pub struct ParityBackend<M> {
+    provider: Arc<M>,
+}
+
+impl<M> ParityBackend<M> {
+    pub fn new(provider: Arc<M>) -> Self {
+        Self { provider }
+    }
+}
+
+#[async_trait]
+impl<M: Middleware + 'static> TraceBackend for ParityBackend<M>
+where
+    M::Error: 'static,
+{
+    async fn trace_call(
+        &self,
+        tx: &Transaction,
+        block: BlockNumber,
+    ) -> Result<StateDiff, WatcherError> {
+        self.provider
+            .trace_call(tx, vec![TraceType::StateDiff], Some(block))
+            .await
+            .map_err(WatcherError::rpc)?
+            .state_diff
+            .ok_or(WatcherError::MissingStateDiff)
+    }
+
+    async fn trace_call_many(
+        &self,
+        txs: &[Transaction],
+        block: BlockNumber,
+    ) -> Result<StateDiff, WatcherError> {
+        let requests = txs
+            .iter()
+            .map(|tx| (tx, vec![TraceType::StateDiff]))
+            .collect();
+
+        self.provider
+            .trace_call_many(requests, Some(block))
+            .await
+            .map_err(WatcherError::rpc)?
+            .pop()
+            .and_then(|trace| trace.state_diff)
+            .ok_or(WatcherError::MissingStateDiff)
+    }
+
+    async fn trace_replay(&self, tx_hash: H256) -> Result<StateDiff, WatcherError> {
+        self.provider
+            .trace_replay_transaction(tx_hash, vec![TraceType::StateDiff])
+            .await
+            .map_err(WatcherError::rpc)?
+            .state_diff
+            .ok_or(WatcherError::MissingStateDiff)
+    }
+
+    async fn trace_replay_block(&self, block: u64) -> Result<Vec<(H256, StateDiff)>, WatcherError> {
+        let traces = self
+            .provider
+            .trace_replay_block_transactions(BlockNumber::from(block), vec![TraceType::StateDiff])
+            .await
+            .map_err(WatcherError::rpc)?;
+
+        traces
+            .into_iter()
+            .map(|trace| {
+                let tx_hash = trace.transaction_hash.ok_or_else(|| {
+                    WatcherError::InvalidTrace(String::from("transaction hash is missing"))
+                })?;
+                let state_diff = trace.state_diff.ok_or(WatcherError::MissingStateDiff)?;
+                Ok((tx_hash, state_diff))
+            })
+            .collect()
+    }
+}
+
+// Geth: debug_traceCall and debug_traceTransaction with prestateTracer in diffMode.
+// debug_traceCallMany is not available, so bundles can't be traced.
+pub struct GethBackend<M> {
+    provider: Arc<M>,
+}
+
+impl<M> GethBackend<M> {
+    pub fn new(provider: Arc<M>) -> Self {
+        Self { provider }
+    }
+}
+
+fn prestate_diff_options() -> GethDebugTracingOptions {
+    GethDebugTracingOptions {
+        tracer: Some(GethDebugTracerType::BuiltInTracer(
+            GethDebugBuiltInTracerType::PreStateTracer,
+        )),
+        tracer_config: Some(GethDebugTracerConfig::BuiltInTracer(
+            GethDebugBuiltInTracerConfig::PreStateTracer(PreStateConfig {
+                diff_mode: Some(true),
+            }),
+        )),
+        ..Default::default()
+    }
+}
+
+#[async_trait]
+impl<M: Middleware + 'static> TraceBackend for GethBackend<M>
+where
+    M::Error: 'static,
+{
+    async fn trace_call(
+        &self,
+        tx: &Transaction,
+        block: BlockNumber,
+    ) -> Result<StateDiff, WatcherError> {
+        let options = GethDebugTracingCallOptions {
+            tracing_options: prestate_diff_options(),
+            ..Default::default()
+        };
+
+        let trace = self
+            .provider
+            .debug_trace_call(tx, Some(BlockId::Number(block)), options)
+            .await
+            .map_err(WatcherError::rpc)?;
+
+        Ok(to_state_diff(diff_mode(trace)?))
+    }
+
+    async fn trace_replay(&self, tx_hash: H256) -> Result<StateDiff, WatcherError> {
+        let trace = self
+            .provider
+            .debug_trace_transaction(tx_hash, prestate_diff_options())
+            .await
+            .map_err(WatcherError::rpc)?;
+
+        Ok(to_state_diff(diff_mode(trace)?))
+    }
+}
+
+fn diff_mode(trace: GethTrace) -> Result<DiffMode, WatcherError> {
+    match trace {
+        GethTrace::Known(GethTraceFrame::PreStateTracer(PreStateFrame::Diff(diff))) => Ok(diff),
+        GethTrace::Unknown(value) => {
+            serde_json::from_value(value).map_err(|e| WatcherError::InvalidTrace(e.to_string()))
+        }
+        _ => Err(WatcherError::InvalidTrace(String::from(
+            "prestateTracer did not return a diff",
+        ))),
+    }
+}
+
+pub(crate) fn diff<T: PartialEq>(pre: Option<T>, post: Option<T>) -> Diff<T> {
+    match (pre, post) {
+        (Some(from), Some(to)) if from == to => Diff::Same,
+        (Some(from), Some(to)) => Diff::Changed(ChangedType { from, to }),
+        (None, Some(to)) => Diff::Born(to),
+        (Some(from), None) => Diff::Died(from),
+        (None, None) => Diff::Same,
+    }
+}
+
+// Maps prestateTracer's diffMode output into the Parity state diff model.
+//
+// `pre` holds the full prior state of every modified account, but only the modified storage slots.
+// `post` holds only the fields that changed, and leaves out storage slots that were set to zero.
+// Accounts that are in `pre` but not in `post` were deleted, accounts only in `post` were created.
+fn to_state_diff(diff_mode: DiffMode) -> StateDiff {
+    let DiffMode { pre, post } = diff_mode;
+
+    let addresses: BTreeSet<_> = pre.keys().chain(post.keys()).copied().collect();
+
+    let mut state_diff = BTreeMap::new();
+
+    for address in addresses {
+        let account_diff = match (pre.get(&address), post.get(&address)) {
+            (Some(pre_state), Some(post_state)) => {
+                // unchanged balance, nonce and code are left out of `post`
+                let changed = |from: Option<_>, to: Option<_>| match to {
+                    Some(to) => diff(Some(from.unwrap_or_default()), Some(to)),
+                    None => Diff::Same,
+                };
+
+                AccountDiff {
+                    balance: changed(pre_state.balance, post_state.balance),
+                    nonce: changed(pre_state.nonce, post_state.nonce),
+                    code: match code_bytes(post_state) {
+                        Some(to) => diff(code_bytes(pre_state), Some(to)),
+                        None => Diff::Same,
+                    },
+                    storage: storage_diff(pre_state, post_state),
+                }
+            }
+            (None, Some(post_state)) => AccountDiff {
+                balance: diff(None, post_state.balance),
+                nonce: diff(None, post_state.nonce),
+                code: diff(None, code_bytes(post_state)),
+                storage: storage_diff(&AccountState::default(), post_state),
+            },
+            (Some(pre_state), None) => AccountDiff {
+                balance: diff(pre_state.balance, None),
+                nonce: diff(pre_state.nonce, None),
+                code: diff(code_bytes(pre_state), None),
+                storage: storage_diff(pre_state, &AccountState::default()),
+            },
+            (None, None) => continue,
+        };
+
+        state_diff.insert(address, account_diff);
+    }
+
+    StateDiff(state_diff)
+}
+
+// A modified slot missing from `post` was cleared, a slot missing from `pre` was zero before
+fn storage_diff(pre_state: &AccountState, post_state: &AccountState) -> BTreeMap<H256, Diff<H256>> {
+    let empty = BTreeMap::new();
+    let pre_storage = pre_state.storage.as_ref().unwrap_or(&empty);
+    let post_storage = post_state.storage.as_ref().unwrap_or(&empty);
+
+    pre_storage
+        .keys()
+        .chain(post_storage.keys())
+        .map(|slot| {
+            let slot_diff = diff(
+                pre_storage.get(slot).copied(),
+                post_storage.get(slot).copied(),
+            );
+            (*slot, slot_diff)
+        })
+        .collect()
+}
+
+fn code_bytes(state: &AccountState) -> Option<Bytes> {
+    state.code.as_ref().and_then(|code| code.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{H160, U256};
+
+    fn account(balance: u64, nonce: u64, storage: &[(u64, u64)]) -> AccountState {
+        AccountState {
+            balance: Some(U256::from(balance)),
+            nonce: Some(U256::from(nonce)),
+            storage: Some(
+                storage
+                    .iter()
+                    .map(|(slot, value)| (slot_key(*slot), slot_key(*value)))
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    fn slot_key(n: u64) -> H256 {
+        H256::from_low_u64_be(n)
+    }
+
+    fn state_diff(pre: &[(u64, AccountState)], post: &[(u64, AccountState)]) -> StateDiff {
+        let accounts = |accounts: &[(u64, AccountState)]| {
+            accounts
+                .iter()
+                .map(|(address, state)| (H160::from_low_u64_be(*address), state.clone()))
+                .collect()
+        };
+
+        to_state_diff(DiffMode {
+            pre: accounts(pre),
+            post: accounts(post),
+        })
+    }
+
+    #[test]
+    fn slot_missing_from_post_died() {
+        let diff = state_diff(
+            &[(1, account(10, 1, &[(1, 5), (2, 7)]))],
+            &[(1, account(10, 1, &[(2, 8)]))],
+        );
+        let storage = &diff.0[&H160::from_low_u64_be(1)].storage;
+
+        assert_eq!(storage[&slot_key(1)], Diff::Died(slot_key(5)));
+        assert_eq!(
+            storage[&slot_key(2)],
+            Diff::Changed(ChangedType {
+                from: slot_key(7),
+                to: slot_key(8)
+            })
+        );
+    }
+
+    #[test]
+    fn slot_missing_from_pre_is_born() {
+        let diff = state_diff(
+            &[(1, account(10, 1, &[]))],
+            &[(1, account(10, 1, &[(3, 9)]))],
+        );
+        let account_diff = &diff.0[&H160::from_low_u64_be(1)];
+
+        assert_eq!(account_diff.storage[&slot_key(3)], Diff::Born(slot_key(9)));
+        // unchanged fields
+        assert_eq!(account_diff.balance, Diff::Same);
+        assert_eq!(account_diff.nonce, Diff::Same);
+    }
+
+    #[test]
+    fn account_only_in_post_is_created() {
+        let diff = state_diff(&[], &[(2, account(100, 1, &[(1, 4)]))]);
+        let account_diff = &diff.0[&H160::from_low_u64_be(2)];
+
+        assert_eq!(account_diff.balance, Diff::Born(U256::from(100)));
+        assert_eq!(account_diff.nonce, Diff::Born(U256::from(1)));
+        assert_eq!(account_diff.storage[&slot_key(1)], Diff::Born(slot_key(4)));
+    }
+
+    #[test]
+    fn fields_left_out_of_post_are_unchanged() {
+        let post = AccountState {
+            balance: Some(U256::from(20)),
+            ..Default::default()
+        };
+        let diff = state_diff(&[(1, account(10, 1, &[]))], &[(1, post)]);
+        let account_diff = &diff.0[&H160::from_low_u64_be(1)];
+
+        assert_eq!(
+            account_diff.balance,
+            Diff::Changed(ChangedType {
+                from: U256::from(10),
+                to: U256::from(20)
+            })
+        );
+        assert_eq!(account_diff.nonce, Diff::Same);
+        assert_eq!(account_diff.code, Diff::Same);
+    }
+}
@@ -0,0 +1,221 @@
+use anyhow::{Context, Result};
+use ethers::types::{H160, H256};
+use log::info;
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use crate::trace::{SwapDirection, TouchedPool};
+
+// Detections are matched on the transaction, pool, token and direction, amounts differ
+// whenever other transactions landed ahead of the pending one
+type DetectionKey = (H256, H160, H160, SwapDirection);
+
+fn key(detection: &TouchedPool) -> DetectionKey {
+    (
+        detection.tx_hash,
+        detection.pool,
+        detection.token,
+        detection.direction,
+    )
+}
+
+// Mempool detections scored against the pool touches of the blocks they were mined in
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Backtest {
+    // detections of transactions mined in the replayed blocks
+    pub predicted: usize,
+    // detections of transactions that were not mined in the replayed blocks, left out of the score
+    pub unresolved: usize,
+    // pool touches found by replaying the blocks
+    pub actual: usize,
+    // pool touches of transactions never seen pending, left out of the recall
+    pub unseen: usize,
+    pub true_positives: usize,
+    pub false_positives: usize,
+    pub false_negatives: usize,
+    pub precision: Option<f64>,
+    pub recall: Option<f64>,
+}
+
+impl Backtest {
+    // `seen` restricts the recall to transactions that were in the mempool, private
+    // transactions can't be detected. Without it every pool touch counts.
+    pub fn evaluate(
+        predicted: &[TouchedPool],
+        actual: &[TouchedPool],
+        mined: &HashSet<H256>,
+        seen: Option<&HashSet<H256>>,
+    ) -> Self {
+        let mut backtest = Backtest::default();
+
+        let predicted: HashSet<_> = predicted.iter().map(key).collect();
+        let actual: HashSet<_> = actual.iter().map(key).collect();
+
+        for detection in &predicted {
+            if !mined.contains(&detection.0) {
+                backtest.unresolved += 1;
+                continue;
+            }
+
+            backtest.predicted += 1;
+            if actual.contains(detection) {
+                backtest.true_positives += 1;
+            } else {
+                backtest.false_positives += 1;
+            }
+        }
+
+        for touch in &actual {
+            if seen.is_some_and(|seen| !seen.contains(&touch.0)) {
+                backtest.unseen += 1;
+                continue;
+            }
+
+            backtest.actual += 1;
+            if !predicted.contains(touch) {
+                backtest.false_negatives += 1;
+            }
+        }
+
+        let ratio = |n: usize, total: usize| (total > 0).then(|| n as f64 / total as f64);
+        backtest.precision = ratio(backtest.true_positives, backtest.predicted);
+        backtest.recall = ratio(backtest.actual - backtest.false_negatives, backtest.actual);
+
+        backtest
+    }
+
+    pub fn report(&self) {
+        let percent = |ratio: Option<f64>| match ratio {
+            Some(ratio) => format!("{:.2}%", ratio * 100.0),
+            None => String::from("n/a"),
+        };
+
+        info!(
+            "Backtest: precision={} recall={} true_positives={} false_positives={} false_negatives={} unresolved={} unseen={}",
+            percent(self.precision),
+            percent(self.recall),
+            self.true_positives,
+            self.false_positives,
+            self.false_negatives,
+            self.unresolved,
+            self.unseen,
+        );
+    }
+}
+
+// Detections written by `watch --output json`, one per line
+pub fn load_detections(path: &Path) -> Result<Vec<TouchedPool>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+
+    let mut detections = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+
+        let detection = serde_json::from_str(&line)
+            .with_context(|| format!("{}:{}: invalid detection", path.display(), i + 1))?;
+        detections.push(detection);
+    }
+
+    Ok(detections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{I256, U256};
+
+    fn detection(tx: u64, pool: u64, amount: i64) -> TouchedPool {
+        TouchedPool {
+            tx_hash: H256::from_low_u64_be(tx),
+            pool: H160::from_low_u64_be(pool),
+            token: H160::from_low_u64_be(100),
+            balance_before: U256::zero(),
+            balance_after: U256::zero(),
+            direction: match amount > 0 {
+                true => SwapDirection::TargetToToken,
+                false => SwapDirection::TokenToTarget,
+            },
+            amount: I256::from(amount),
+            score: None,
+            first_seen: Vec::new(),
+        }
+    }
+
+    fn hashes(txs: &[u64]) -> HashSet<H256> {
+        txs.iter().map(|tx| H256::from_low_u64_be(*tx)).collect()
+    }
+
+    #[test]
+    fn unmined_detections_and_unseen_touches_are_left_out() {
+        // mined and right, with a different amount than the replay; mined and wrong; not mined
+        let predicted = [
+            detection(1, 1, 10),
+            detection(2, 2, 10),
+            detection(9, 1, 10),
+        ];
+        // detected; missed; never in the mempool
+        let actual = [detection(1, 1, 12), detection(3, 3, -5), detection(4, 4, 5)];
+
+        let backtest = Backtest::evaluate(
+            &predicted,
+            &actual,
+            &hashes(&[1, 2, 3, 4]),
+            Some(&hashes(&[1, 2, 3, 9])),
+        );
+
+        assert_eq!(backtest.predicted, 2);
+        assert_eq!(backtest.unresolved, 1);
+        assert_eq!(backtest.actual, 2);
+        assert_eq!(backtest.unseen, 1);
+        assert_eq!(backtest.true_positives, 1);
+        assert_eq!(backtest.false_positives, 1);
+        assert_eq!(backtest.false_negatives, 1);
+        assert_eq!(backtest.precision, Some(0.5));
+        assert_eq!(backtest.recall, Some(0.5));
+    }
+
+    #[test]
+    fn every_touch_counts_without_a_recording() {
+        let predicted = [detection(1, 1, 10)];
+        let actual = [detection(1, 1, 10), detection(3, 3, -5), detection(4, 4, 5)];
+
+        let backtest = Backtest::evaluate(&predicted, &actual, &hashes(&[1, 3, 4]), None);
+
+        assert_eq!(backtest.actual, 3);
+        assert_eq!(backtest.unseen, 0);
+        assert_eq!(backtest.false_negatives, 2);
+        assert_eq!(backtest.precision, Some(1.0));
+        assert_eq!(backtest.recall, Some(1.0 / 3.0));
+    }
+
+    #[test]
+    fn the_direction_has_to_match() {
+        let backtest = Backtest::evaluate(
+            &[detection(1, 1, 10)],
+            &[detection(1, 1, -10)],
+            &hashes(&[1]),
+            None,
+        );
+
+        assert_eq!(backtest.true_positives, 0);
+        assert_eq!(backtest.false_positives, 1);
+        assert_eq!(backtest.false_negatives, 1);
+    }
+
+    #[test]
+    fn nothing_to_score_has_no_ratios() {
+        let backtest = Backtest::evaluate(&[detection(9, 1, 10)], &[], &HashSet::new(), None);
+
+        assert_eq!(backtest.unresolved, 1);
+        assert_eq!(backtest.precision, None);
+        assert_eq!(backtest.recall, None);
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::VecDeque;
+
+use crate::trace::NewBlock;
+
+#[derive(Debug, Clone)]
+pub enum ChainUpdate {
+    // the header builds on the current tip
+    Extended,
+    // the header replaces blocks of the tracked chain
+    Reorg {
+        dropped: Vec<NewBlock>,
+        new: Vec<NewBlock>,
+    },
+    // the parent is unknown and the header is ahead of the tip (missed headers, e.g. after a
+    // reconnect), tracking restarts from this header
+    Gap,
+    // the header is already tracked
+    Duplicate,
+}
+
+// Keeps the last `depth` headers of the canonical chain and checks every new header's parent
+pub struct ChainTracker {
+    depth: usize,
+    headers: VecDeque<NewBlock>,
+}
+
+impl ChainTracker {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            headers: VecDeque::with_capacity(depth + 1),
+        }
+    }
+
+    pub fn tip(&self) -> Option<&NewBlock> {
+        self.headers.back()
+    }
+
+    pub fn push(&mut self, block: NewBlock) -> ChainUpdate {
+        let tip = self.tip().map(|tip| (tip.hash, tip.number));
+
+        let update = match tip {
+            None => ChainUpdate::Extended,
+            Some((tip_hash, _)) if tip_hash == block.parent_hash => ChainUpdate::Extended,
+            Some(_) if self.headers.iter().any(|header| header.hash == block.hash) => {
+                return ChainUpdate::Duplicate;
+            }
+            Some((_, tip_number)) => {
+                let parent = self
+                    .headers
+                    .iter()
+                    .position(|header| header.hash == block.parent_hash);
+
+                match parent {
+                    // the parent is tracked, everything after it was orphaned
+                    Some(parent) => ChainUpdate::Reorg {
+                        dropped: self.headers.drain(parent + 1..).collect(),
+                        new: vec![block.clone()],
+                    },
+                    None if block.number > tip_number + 1 => {
+                        self.headers.clear();
+                        ChainUpdate::Gap
+                    }
+                    // the parent is unknown, so the tracked header at its height was orphaned too and
+                    // the fork point is deeper than `depth`, drop everything from the parent's height up
+                    None => {
+                        let parent_number = block.number.saturating_sub(1.into());
+                        let keep = self
+                            .headers
+                            .iter()
+                            .take_while(|header| header.number < parent_number)
+                            .count();
+                        let dropped = self.headers.drain(keep..).collect();
+                        self.headers.clear();
+
+                        ChainUpdate::Reorg {
+                            dropped,
+                            new: vec![block.clone()],
+                        }
+                    }
+                }
+            }
+        };
+
+        self.headers.push_back(block);
+        while self.headers.len() > self.depth {
+            self.headers.pop_front();
+        }
+
+        update
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{H256, U64};
+
+    // `fork` tells apart blocks of the same height
+    fn block(number: u64, fork: u64, parent_fork: u64) -> NewBlock {
+        NewBlock {
+            number: U64::from(number),
+            hash: H256::from_low_u64_be(number * 100 + fork),
+            parent_hash: H256::from_low_u64_be((number - 1) * 100 + parent_fork),
+            ..Default::default()
+        }
+    }
+
+    fn numbers(blocks: &[NewBlock]) -> Vec<u64> {
+        blocks.iter().map(|block| block.number.as_u64()).collect()
+    }
+
+    fn tracker(depth: usize, blocks: &[NewBlock]) -> ChainTracker {
+        let mut tracker = ChainTracker::new(depth);
+        for block in blocks {
+            tracker.push(block.clone());
+        }
+        tracker
+    }
+
+    #[test]
+    fn first_header_and_children_extend_the_chain() {
+        let mut tracker = ChainTracker::new(8);
+
+        assert!(matches!(
+            tracker.push(block(10, 0, 0)),
+            ChainUpdate::Extended
+        ));
+        assert!(matches!(
+            tracker.push(block(11, 0, 0)),
+            ChainUpdate::Extended
+        ));
+        assert_eq!(tracker.tip().unwrap().hash, block(11, 0, 0).hash);
+    }
+
+    #[test]
+    fn tracked_headers_are_duplicates() {
+        let mut tracker = tracker(8, &[block(10, 0, 0), block(11, 0, 0)]);
+
+        assert!(matches!(
+            tracker.push(block(10, 0, 0)),
+            ChainUpdate::Duplicate
+        ));
+        assert!(matches!(
+            tracker.push(block(11, 0, 0)),
+            ChainUpdate::Duplicate
+        ));
+        assert_eq!(tracker.tip().unwrap().hash, block(11, 0, 0).hash);
+    }
+
+    #[test]
+    fn sibling_of_tracked_blocks_drops_everything_after_its_parent() {
+        let mut tracker = tracker(8, &[block(10, 0, 0), block(11, 0, 0), block(12, 0, 0)]);
+
+        match tracker.push(block(11, 1, 0)) {
+            ChainUpdate::Reorg { dropped, new } => {
+                assert_eq!(numbers(&dropped), vec![11, 12]);
+                assert_eq!(new[0].hash, block(11, 1, 0).hash);
+            }
+            update => panic!("expected a reorg, got {:?}", update),
+        }
+        assert_eq!(tracker.tip().unwrap().hash, block(11, 1, 0).hash);
+
+        // the new branch extends from there
+        assert!(matches!(
+            tracker.push(block(12, 1, 1)),
+            ChainUpdate::Extended
+        ));
+    }
+
+    #[test]
+    fn unknown_parent_ahead_of_the_tip_is_a_gap() {
+        let mut tracker = tracker(8, &[block(10, 0, 0), block(11, 0, 0)]);
+
+        assert!(matches!(tracker.push(block(14, 0, 0)), ChainUpdate::Gap));
+        assert_eq!(tracker.tip().unwrap().hash, block(14, 0, 0).hash);
+        // tracking restarted, the old headers are gone
+        assert!(matches!(
+            tracker.push(block(11, 0, 0)),
+            ChainUpdate::Reorg { .. }
+        ));
+    }
+
+    #[test]
+    fn unknown_parent_at_or_below_the_tip_is_a_deep_reorg() {
+        let mut tracker = tracker(
+            8,
+            &[
+                block(10, 0, 0),
+                block(11, 0, 0),
+                block(12, 0, 0),
+                block(13, 0, 0),
+            ],
+        );
+
+        // its parent 11' was never tracked, so 11 was orphaned as well
+        match tracker.push(block(12, 1, 1)) {
+            ChainUpdate::Reorg { dropped, new } => {
+                assert_eq!(numbers(&dropped), vec![11, 12, 13]);
+                assert_eq!(new[0].hash, block(12, 1, 1).hash);
+            }
+            update => panic!("expected a reorg, got {:?}", update),
+        }
+        assert_eq!(tracker.tip().unwrap().hash, block(12, 1, 1).hash);
+        assert!(matches!(
+            tracker.push(block(13, 1, 1)),
+            ChainUpdate::Extended
+        ));
+    }
+
+    #[test]
+    fn unknown_parent_right_after_the_tip_orphans_the_tip() {
+        let mut tracker = tracker(8, &[block(10, 0, 0), block(11, 0, 0)]);
+
+        // 12 builds on 11', which replaced the tracked 11
+        match tracker.push(block(12, 0, 1)) {
+            ChainUpdate::Reorg { dropped, new } => {
+                assert_eq!(numbers(&dropped), vec![11]);
+                assert_eq!(new[0].hash, block(12, 0, 1).hash);
+            }
+            update => panic!("expected a reorg, got {:?}", update),
+        }
+        assert_eq!(tracker.tip().unwrap().hash, block(12, 0, 1).hash);
+    }
+
+    #[test]
+    fn only_the_last_depth_headers_are_kept() {
+        let chain = [
+            block(10, 0, 0),
+            block(11, 0, 0),
+            block(12, 0, 0),
+            block(13, 0, 0),
+        ];
+
+        // 10 was trimmed, so a sibling of 11 can't find its parent anymore
+        match tracker(3, &chain).push(block(11, 1, 0)) {
+            ChainUpdate::Reorg { dropped, .. } => assert_eq!(numbers(&dropped), vec![11, 12, 13]),
+            update => panic!("expected a reorg, got {:?}", update),
+        }
+
+        // while a sibling of 12 still finds 11
+        match tracker(3, &chain).push(block(12, 1, 0)) {
+            ChainUpdate::Reorg { dropped, .. } => assert_eq!(numbers(&dropped), vec![12, 13]),
+            update => panic!("expected a reorg, got {:?}", update),
+        }
+    }
+}
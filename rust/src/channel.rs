@@ -0,0 +1,247 @@
+use log::warn;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+};
+
+use crate::config::LagPolicy;
+use crate::metrics::Metrics;
+use crate::trace::Event;
+
+// Producers start waiting when the channel is this full (in percent)
+const THROTTLE_THRESHOLD: usize = 75;
+const THROTTLE_DELAY: Duration = Duration::from_millis(5);
+
+#[derive(Clone)]
+pub enum EventSender {
+    Broadcast {
+        sender: broadcast::Sender<Event>,
+        capacity: usize,
+        throttle: bool,
+        metrics: Arc<Metrics>,
+    },
+    Bounded(mpsc::Sender<Event>),
+}
+
+pub enum EventReceiver {
+    Broadcast {
+        receiver: broadcast::Receiver<Event>,
+        resubscribe: bool,
+        metrics: Arc<Metrics>,
+    },
+    Bounded(mpsc::Receiver<Event>),
+}
+
+pub fn event_channel(
+    policy: LagPolicy,
+    capacity: usize,
+    metrics: Arc<Metrics>,
+) -> (EventSender, EventReceiver) {
+    match policy {
+        LagPolicy::Resubscribe | LagPolicy::Throttle => {
+            let (sender, receiver) = broadcast::channel(capacity);
+            (
+                EventSender::Broadcast {
+                    sender,
+                    capacity,
+                    throttle: policy == LagPolicy::Throttle,
+                    metrics: metrics.clone(),
+                },
+                EventReceiver::Broadcast {
+                    receiver,
+                    resubscribe: policy == LagPolicy::Resubscribe,
+                    metrics,
+                },
+            )
+        }
+        LagPolicy::Backpressure => {
+            let (sender, receiver) = mpsc::channel(capacity);
+            (
+                EventSender::Bounded(sender),
+                EventReceiver::Bounded(receiver),
+            )
+        }
+    }
+}
+
+impl EventSender {
+    // Returns false once the handler is gone
+    pub async fn send(&self, event: Event) -> bool {
+        match self {
+            EventSender::Broadcast {
+                sender,
+                capacity,
+                throttle,
+                metrics,
+            } => {
+                // only pending transactions wait, headers (and the reorgs and reconnects around
+                // them) go through right away, the handler needs them to trace anything
+                let throttled = *throttle && matches!(event, Event::Transaction { .. });
+
+                if throttled && sender.len() * 100 >= capacity * THROTTLE_THRESHOLD {
+                    Metrics::incr(&metrics.throttled_sends, 1);
+                    while sender.len() * 100 >= capacity * THROTTLE_THRESHOLD {
+                        tokio::time::sleep(THROTTLE_DELAY).await;
+                    }
+                }
+                sender.send(event).is_ok()
+            }
+            EventSender::Bounded(sender) => sender.send(event).await.is_ok(),
+        }
+    }
+}
+
+impl EventReceiver {
+    // Returns None once every sender is gone
+    pub async fn recv(&mut self) -> Option<Event> {
+        match self {
+            EventReceiver::Broadcast {
+                receiver,
+                resubscribe,
+                metrics,
+            } => loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some(event),
+                    Err(RecvError::Lagged(skipped)) => {
+                        Metrics::incr(&metrics.lagged_events, skipped);
+
+                        if *resubscribe {
+                            // drop the backlog as well, it is already behind the chain
+                            *receiver = receiver.resubscribe();
+                            Metrics::incr(&metrics.resubscribes, 1);
+                        }
+
+                        warn!(
+                            "Event handler lagged, skipped {} events ({} in total)",
+                            skipped,
+                            Metrics::get(&metrics.lagged_events)
+                        );
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            },
+            EventReceiver::Bounded(receiver) => receiver.recv().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::{NewBlock, Received};
+    use ethers::types::{Transaction, H256, U64};
+    use std::time::SystemTime;
+    use tokio::time::timeout;
+
+    // long enough for a send that isn't held back
+    const WAIT: Duration = Duration::from_millis(50);
+
+    fn received() -> Received {
+        Received {
+            node: 0,
+            at: SystemTime::now(),
+        }
+    }
+
+    fn tx(hash: u64) -> Event {
+        Event::Transaction {
+            tx: Box::new(Transaction {
+                hash: H256::from_low_u64_be(hash),
+                ..Default::default()
+            }),
+            received: received(),
+        }
+    }
+
+    fn header(number: u64) -> Event {
+        Event::NewBlock {
+            block: Box::new(NewBlock {
+                number: U64::from(number),
+                ..Default::default()
+            }),
+            received: received(),
+        }
+    }
+
+    fn tx_hash(event: Event) -> u64 {
+        match event {
+            Event::Transaction { tx, .. } => tx.hash.to_low_u64_be(),
+            event => panic!("expected a transaction, got {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn throttle_holds_back_transactions_but_not_headers() {
+        let metrics = Arc::new(Metrics::default());
+        let (sender, mut receiver) = event_channel(LagPolicy::Throttle, 4, metrics.clone());
+
+        // 3 of 4 is the threshold
+        for hash in 0..3 {
+            assert!(timeout(WAIT, sender.send(tx(hash))).await.unwrap());
+        }
+
+        let held_back = {
+            let sender = sender.clone();
+            tokio::spawn(async move { sender.send(tx(3)).await })
+        };
+        tokio::time::sleep(WAIT).await;
+        assert!(!held_back.is_finished());
+
+        // headers go through the full channel
+        assert!(timeout(WAIT, sender.send(header(10))).await.unwrap());
+
+        // the transaction follows once the handler catches up
+        assert_eq!(tx_hash(receiver.recv().await.unwrap()), 0);
+        assert_eq!(tx_hash(receiver.recv().await.unwrap()), 1);
+        assert!(timeout(WAIT * 4, held_back).await.unwrap().unwrap());
+
+        assert_eq!(Metrics::get(&metrics.throttled_sends), 1);
+        assert_eq!(Metrics::get(&metrics.lagged_events), 0);
+    }
+
+    #[tokio::test]
+    async fn backpressure_blocks_instead_of_dropping() {
+        let metrics = Arc::new(Metrics::default());
+        let (sender, mut receiver) = event_channel(LagPolicy::Backpressure, 2, metrics.clone());
+
+        let sending = tokio::spawn(async move {
+            for hash in 0..10 {
+                assert!(sender.send(tx(hash)).await);
+            }
+        });
+        tokio::time::sleep(WAIT).await;
+        // blocked on the full channel
+        assert!(!sending.is_finished());
+
+        let mut hashes = Vec::new();
+        while let Some(event) = receiver.recv().await {
+            hashes.push(tx_hash(event));
+        }
+        sending.await.unwrap();
+
+        assert_eq!(hashes, (0..10).collect::<Vec<_>>());
+        assert_eq!(Metrics::get(&metrics.lagged_events), 0);
+    }
+
+    #[tokio::test]
+    async fn resubscribe_skips_the_backlog() {
+        let metrics = Arc::new(Metrics::default());
+        let (sender, mut receiver) = event_channel(LagPolicy::Resubscribe, 2, metrics.clone());
+
+        // never waits, the oldest events are overwritten
+        for hash in 0..5 {
+            assert!(timeout(WAIT, sender.send(tx(hash))).await.unwrap());
+        }
+
+        let next = tokio::spawn(async move { receiver.recv().await });
+        tokio::time::sleep(WAIT).await;
+        // the backlog is gone, the handler continues with the next event
+        assert!(!next.is_finished());
+        assert!(sender.send(tx(5)).await);
+        assert_eq!(tx_hash(next.await.unwrap().unwrap()), 5);
+
+        assert_eq!(Metrics::get(&metrics.lagged_events), 3);
+        assert_eq!(Metrics::get(&metrics.resubscribes), 1);
+    }
+}
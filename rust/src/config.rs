@@ -0,0 +1,609 @@
+use anyhow::{anyhow, bail, Context, Result};
+use cfmms::dex::DexVariant;
+use ethers::types::Address;
+use serde::Deserialize;
+use std::{collections::HashSet, path::Path};
+
+use crate::pools::DexSpec;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WatcherConfig {
+    pub target_tokens: Vec<Address>,
+    #[serde(default)]
+    pub rpc: RpcConfig,
+    #[serde(default)]
+    pub checkpoint: CheckpointConfig,
+    #[serde(default)]
+    pub channels: ChannelConfig,
+    #[serde(default)]
+    pub tracing: TracingConfig,
+    #[serde(default)]
+    pub chain: ChainConfig,
+    #[serde(default)]
+    pub mempool: MempoolConfig,
+    #[serde(default)]
+    pub dedup: DedupConfig,
+    #[serde(default)]
+    pub recording: RecordingConfig,
+    pub dexes: Vec<DexConfig>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RpcConfig {
+    // falls back to the WSS_URL environment variable
+    pub wss_url: Option<String>,
+    // several nodes to merge pending transactions and headers from, instead of wss_url
+    #[serde(default)]
+    pub wss_urls: Vec<String>,
+    // used instead of the websocket endpoint when set
+    pub ipc_path: Option<String>,
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    // doubled after every failed attempt, up to max_delay_ms
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    // a subscription that stays silent this long is considered dead
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay_ms: 1000,
+            max_delay_ms: 30000,
+            idle_timeout_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CheckpointConfig {
+    pub path: String,
+    pub step: usize,
+}
+
+impl Default for CheckpointConfig {
+    fn default() -> Self {
+        Self {
+            path: String::from(".cfmms-checkpoint.json"),
+            step: 100000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ChannelConfig {
+    pub event_capacity: usize,
+    pub pending_tx_buffer: usize,
+    pub lag_policy: LagPolicy,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self {
+            event_capacity: 512,
+            pending_tx_buffer: 256,
+            lag_policy: LagPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ChainConfig {
+    // headers kept to detect reorgs, deeper reorgs are still noticed but not fully reported
+    pub depth: usize,
+}
+
+impl Default for ChainConfig {
+    fn default() -> Self {
+        Self { depth: 64 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MempoolConfig {
+    // pending transactions not mined after this many blocks are forgotten
+    pub max_age_blocks: u64,
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        Self { max_age_blocks: 64 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DedupConfig {
+    // a pending transaction hash is traced at most once within this window
+    pub ttl_secs: u64,
+    // hashes remembered at most, the oldest are forgotten first
+    pub max_entries: usize,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 600,
+            max_entries: 100000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RecordingConfig {
+    // directory the watched events are written to, nothing is recorded when unset
+    pub dir: Option<String>,
+    // a new file is started once the current one grows past this size
+    pub max_file_bytes: u64,
+    // the oldest files are deleted beyond this count, 0 keeps them all
+    pub max_files: usize,
+    // events queued for the writer, they are dropped (and counted) once it falls this far behind
+    pub buffer: usize,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            max_file_bytes: 64 * 1024 * 1024,
+            max_files: 0,
+            buffer: 8192,
+        }
+    }
+}
+
+// What happens when the event handler can't keep up with the streams
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LagPolicy {
+    // the handler drops its backlog and continues with the newest events
+    #[default]
+    Resubscribe,
+    // producers wait while the event channel is nearly full
+    Throttle,
+    // bounded mpsc channel, producers wait until there is room and nothing is dropped
+    Backpressure,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TracingConfig {
+    pub backend: BackendKind,
+    // pending transactions traced at the same time
+    pub max_in_flight: usize,
+    // a trace that takes longer is dropped and counted as rpc_timeout
+    pub timeout_ms: u64,
+    // random wei added to the predicted base fee before filtering, 0 disables it
+    pub base_fee_jitter: u64,
+    // state pending transactions are traced on
+    pub target: TraceTarget,
+    // with target = "bundle", higher-tip pending transactions executed first
+    pub bundle_size: usize,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            backend: BackendKind::default(),
+            max_in_flight: 32,
+            timeout_ms: 10000,
+            base_fee_jitter: 0,
+            target: TraceTarget::default(),
+            bundle_size: 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    // trace_call with stateDiff (Erigon, Nethermind, Reth)
+    #[default]
+    Parity,
+    // debug_traceCall with prestateTracer in diffMode
+    Geth,
+    // local simulation with revm on a fork of the latest block
+    Revm,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceTarget {
+    // the last block seen on newHeads
+    #[default]
+    Latest,
+    // the node's pending block
+    Pending,
+    // the last block, after the pending transactions paying a higher tip
+    Bundle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DexKind {
+    UniswapV2,
+    UniswapV3,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DexConfig {
+    pub name: String,
+    pub factory: Address,
+    pub variant: DexKind,
+    pub creation_block: u64,
+    #[serde(default = "default_fee")]
+    pub fee: u64,
+}
+
+fn default_fee() -> u64 {
+    300
+}
+
+impl DexConfig {
+    pub fn spec(&self) -> DexSpec {
+        DexSpec {
+            name: self.name.clone(),
+            factory: self.factory,
+            variant: match self.variant {
+                DexKind::UniswapV2 => DexVariant::UniswapV2,
+                DexKind::UniswapV3 => DexVariant::UniswapV3,
+            },
+            creation_block: self.creation_block,
+            fee: self.fee,
+        }
+    }
+}
+
+impl WatcherConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        let config: WatcherConfig = toml::from_str(&content)
+            .with_context(|| format!("failed to parse config file {}", path.display()))?;
+
+        config
+            .validate()
+            .with_context(|| format!("invalid config file {}", path.display()))?;
+
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.target_tokens.is_empty() {
+            bail!("target_tokens: at least one token is required");
+        }
+
+        let mut tokens = HashSet::new();
+        for token in &self.target_tokens {
+            if !tokens.insert(token) {
+                bail!("target_tokens: {:?} is listed more than once", token);
+            }
+        }
+
+        if self.dexes.is_empty() {
+            bail!("dexes: at least one [[dexes]] entry is required");
+        }
+
+        let mut factories = HashSet::new();
+        for dex in &self.dexes {
+            if !factories.insert(dex.factory) {
+                bail!(
+                    "dexes: factory {:?} ({}) is listed more than once",
+                    dex.factory,
+                    dex.name
+                );
+            }
+            if dex.variant == DexKind::UniswapV2 && (dex.fee == 0 || dex.fee >= 10000) {
+                bail!(
+                    "dexes: fee of {} must be between 1 and 9999 (300 = 0.3%), got {}",
+                    dex.name,
+                    dex.fee
+                );
+            }
+        }
+
+        if self.rpc.wss_url.is_some() && !self.rpc.wss_urls.is_empty() {
+            bail!("rpc.wss_urls: can't be combined with rpc.wss_url, list every node in wss_urls");
+        }
+
+        match &self.rpc.ipc_path {
+            Some(ipc_path) => {
+                if !Path::new(ipc_path).exists() {
+                    bail!("rpc.ipc_path: {} does not exist", ipc_path);
+                }
+            }
+            None => {
+                let mut urls = HashSet::new();
+                for wss_url in self.wss_urls()? {
+                    if !(wss_url.starts_with("ws://") || wss_url.starts_with("wss://")) {
+                        bail!(
+                            "rpc.wss_url: expected a ws:// or wss:// url, got {}",
+                            wss_url
+                        );
+                    }
+                    if !urls.insert(wss_url.clone()) {
+                        bail!("rpc.wss_urls: {} is listed more than once", wss_url);
+                    }
+                }
+            }
+        }
+
+        let reconnect = &self.rpc.reconnect;
+        if reconnect.initial_delay_ms == 0 {
+            bail!("rpc.reconnect.initial_delay_ms: must be greater than 0");
+        }
+        if reconnect.max_delay_ms < reconnect.initial_delay_ms {
+            bail!(
+                "rpc.reconnect.max_delay_ms: must be at least initial_delay_ms ({})",
+                reconnect.initial_delay_ms
+            );
+        }
+        if reconnect.idle_timeout_secs == 0 {
+            bail!("rpc.reconnect.idle_timeout_secs: must be greater than 0");
+        }
+
+        if self.checkpoint.path.is_empty() {
+            bail!("checkpoint.path: must not be empty");
+        }
+        if let Some(dir) = Path::new(&self.checkpoint.path).parent() {
+            if !dir.as_os_str().is_empty() && !dir.is_dir() {
+                bail!(
+                    "checkpoint.path: directory {} does not exist",
+                    dir.display()
+                );
+            }
+        }
+        if self.checkpoint.step == 0 {
+            bail!("checkpoint.step: must be greater than 0");
+        }
+
+        if self.channels.event_capacity == 0 {
+            bail!("channels.event_capacity: must be greater than 0");
+        }
+        if self.channels.pending_tx_buffer == 0 {
+            bail!("channels.pending_tx_buffer: must be greater than 0");
+        }
+
+        if self.tracing.max_in_flight == 0 {
+            bail!("tracing.max_in_flight: must be greater than 0");
+        }
+        if self.tracing.timeout_ms == 0 {
+            bail!("tracing.timeout_ms: must be greater than 0");
+        }
+
+        if self.tracing.target == TraceTarget::Bundle && self.tracing.backend == BackendKind::Geth {
+            bail!("tracing.target: \"bundle\" is not supported by the geth backend");
+        }
+        if self.tracing.target == TraceTarget::Bundle && self.tracing.bundle_size == 0 {
+            bail!("tracing.bundle_size: must be greater than 0");
+        }
+
+        if self.chain.depth == 0 {
+            bail!("chain.depth: must be greater than 0");
+        }
+
+        if self.mempool.max_age_blocks == 0 {
+            bail!("mempool.max_age_blocks: must be greater than 0");
+        }
+
+        if self.dedup.ttl_secs == 0 {
+            bail!("dedup.ttl_secs: must be greater than 0");
+        }
+        if self.dedup.max_entries == 0 {
+            bail!("dedup.max_entries: must be greater than 0");
+        }
+
+        if let Some(dir) = &self.recording.dir {
+            if dir.is_empty() {
+                bail!("recording.dir: must not be empty");
+            }
+            if self.recording.max_file_bytes == 0 {
+                bail!("recording.max_file_bytes: must be greater than 0");
+            }
+            if self.recording.buffer == 0 {
+                bail!("recording.buffer: must be greater than 0");
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn wss_url(&self) -> Result<String> {
+        match &self.rpc.wss_url {
+            Some(url) => Ok(url.clone()),
+            None => std::env::var("WSS_URL")
+                .map_err(|_| anyhow!("rpc.wss_url: not set in the config and WSS_URL is missing")),
+        }
+    }
+
+    // Every node to stream from, the first one is also used for tracing
+    pub fn wss_urls(&self) -> Result<Vec<String>> {
+        if self.rpc.wss_urls.is_empty() {
+            Ok(vec![self.wss_url()?])
+        } else {
+            Ok(self.rpc.wss_urls.clone())
+        }
+    }
+
+    pub fn dex_specs(&self) -> Vec<DexSpec> {
+        self.dexes.iter().map(|dex| dex.spec()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL: &str = r#"
+        target_tokens = ["0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"]
+
+        [rpc]
+        wss_url = "ws://localhost:8546"
+
+        [[dexes]]
+        name = "Uniswap V2"
+        factory = "0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f"
+        variant = "uniswap_v2"
+        creation_block = 10000835
+    "#;
+
+    fn config() -> WatcherConfig {
+        toml::from_str(MINIMAL).unwrap()
+    }
+
+    // breaks one setting of a valid config
+    type Invalidate = fn(&mut WatcherConfig);
+
+    #[test]
+    fn example_config_is_valid() {
+        let mut config: WatcherConfig =
+            toml::from_str(include_str!("../config.example.toml")).unwrap();
+        // WSS_URL comes from the environment otherwise
+        config.rpc.wss_url = Some(String::from("ws://localhost:8546"));
+
+        config.validate().unwrap();
+        assert_eq!(config.target_tokens.len(), 1);
+        assert_eq!(config.dexes.len(), 3);
+        assert_eq!(config.tracing.backend, BackendKind::Parity);
+        assert_eq!(config.recording.dir, None);
+    }
+
+    #[test]
+    fn missing_sections_get_defaults() {
+        let config = config();
+
+        config.validate().unwrap();
+        assert_eq!(config.checkpoint.path, ".cfmms-checkpoint.json");
+        assert_eq!(config.channels.event_capacity, 512);
+        assert_eq!(config.dexes[0].fee, 300);
+        assert_eq!(
+            config.wss_urls().unwrap(),
+            vec![String::from("ws://localhost:8546")]
+        );
+    }
+
+    #[test]
+    fn several_nodes_are_listed_in_wss_urls() {
+        let mut config = config();
+        config.rpc.wss_url = None;
+        config.rpc.wss_urls = vec![
+            String::from("ws://node-a:8546"),
+            String::from("wss://node-b:8546"),
+        ];
+
+        config.validate().unwrap();
+        assert_eq!(config.wss_urls().unwrap(), config.rpc.wss_urls);
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let cases: Vec<(Invalidate, &str)> = vec![
+            (|c| c.target_tokens.clear(), "target_tokens: at least one"),
+            (
+                |c| c.target_tokens.push(c.target_tokens[0]),
+                "target_tokens: 0xc02a",
+            ),
+            (|c| c.dexes.clear(), "dexes: at least one"),
+            (|c| c.dexes.push(c.dexes[0].clone()), "dexes: factory"),
+            (|c| c.dexes[0].fee = 0, "dexes: fee of Uniswap V2"),
+            (|c| c.dexes[0].fee = 10000, "dexes: fee of Uniswap V2"),
+            (
+                |c| c.rpc.wss_urls = vec![String::from("ws://node-b:8546")],
+                "rpc.wss_urls: can't be combined",
+            ),
+            (
+                |c| c.rpc.wss_url = Some(String::from("http://localhost:8545")),
+                "rpc.wss_url: expected",
+            ),
+            (
+                |c| {
+                    c.rpc.wss_url = None;
+                    c.rpc.wss_urls = vec![String::from("ws://a:8546"); 2];
+                },
+                "rpc.wss_urls: ws://a:8546 is listed more than once",
+            ),
+            (
+                |c| c.rpc.ipc_path = Some(String::from("/nonexistent/geth.ipc")),
+                "rpc.ipc_path",
+            ),
+            (
+                |c| c.rpc.reconnect.initial_delay_ms = 0,
+                "rpc.reconnect.initial_delay_ms",
+            ),
+            (
+                |c| c.rpc.reconnect.max_delay_ms = 10,
+                "rpc.reconnect.max_delay_ms",
+            ),
+            (|c| c.checkpoint.path.clear(), "checkpoint.path"),
+            (
+                |c| c.checkpoint.path = String::from("/nonexistent/checkpoint.json"),
+                "checkpoint.path: directory",
+            ),
+            (|c| c.checkpoint.step = 0, "checkpoint.step"),
+            (|c| c.channels.event_capacity = 0, "channels.event_capacity"),
+            (
+                |c| c.channels.pending_tx_buffer = 0,
+                "channels.pending_tx_buffer",
+            ),
+            (|c| c.tracing.max_in_flight = 0, "tracing.max_in_flight"),
+            (|c| c.tracing.timeout_ms = 0, "tracing.timeout_ms"),
+            (
+                |c| {
+                    c.tracing.target = TraceTarget::Bundle;
+                    c.tracing.backend = BackendKind::Geth;
+                },
+                "tracing.target",
+            ),
+            (
+                |c| {
+                    c.tracing.target = TraceTarget::Bundle;
+                    c.tracing.bundle_size = 0;
+                },
+                "tracing.bundle_size",
+            ),
+            (|c| c.chain.depth = 0, "chain.depth"),
+            (|c| c.mempool.max_age_blocks = 0, "mempool.max_age_blocks"),
+            (|c| c.dedup.ttl_secs = 0, "dedup.ttl_secs"),
+            (|c| c.dedup.max_entries = 0, "dedup.max_entries"),
+            (|c| c.recording.dir = Some(String::new()), "recording.dir"),
+            (
+                |c| {
+                    c.recording.dir = Some(String::from("recordings"));
+                    c.recording.buffer = 0;
+                },
+                "recording.buffer",
+            ),
+        ];
+
+        for (invalidate, expected) in cases {
+            let mut config = config();
+            invalidate(&mut config);
+
+            let e = config.validate().unwrap_err().to_string();
+            assert!(e.starts_with(expected), "{:?} is not {:?}", e, expected);
+        }
+    }
+
+    #[test]
+    fn recording_limits_are_only_checked_when_recording() {
+        let mut config = config();
+        config.recording.max_file_bytes = 0;
+        config.validate().unwrap();
+
+        config.recording.dir = Some(String::from("recordings"));
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,698 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use ethers::{
+    providers::{
+        Ipc, JsonRpcClient, Middleware, Provider, ProviderError, PubsubClient, RpcError, Ws,
+    },
+    types::H256,
+};
+use futures::stream::FuturesUnordered;
+use log::{debug, error, info, warn};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{
+    collections::VecDeque,
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio_stream::StreamExt;
+
+use crate::chain::{ChainTracker, ChainUpdate};
+use crate::channel::EventSender;
+use crate::config::ReconnectConfig;
+use crate::error::WatcherError;
+use crate::metrics::Metrics;
+use crate::trace::{Event, NewBlock, Received, Subscription};
+
+// Opens a fresh pubsub connection to the node, called again every time a subscription dies
+#[async_trait]
+pub trait Connector: Send + Sync + 'static {
+    type Transport: PubsubClient + 'static;
+
+    async fn connect(&self) -> Result<Provider<Self::Transport>>;
+
+    // Identifies the node in logs and reports
+    fn name(&self) -> String;
+}
+
+pub struct WsConnector {
+    url: String,
+}
+
+impl WsConnector {
+    pub fn new(url: String) -> Self {
+        Self { url }
+    }
+}
+
+#[async_trait]
+impl Connector for WsConnector {
+    type Transport = Ws;
+
+    async fn connect(&self) -> Result<Provider<Ws>> {
+        Ok(Provider::<Ws>::connect(&self.url).await?)
+    }
+
+    // host and port only, urls of hosted nodes usually carry an API key
+    fn name(&self) -> String {
+        let host = match self.url.split_once("://") {
+            Some((_, rest)) => rest,
+            None => &self.url,
+        };
+        let host = host.split(['/', '?']).next().unwrap_or_default();
+        match host.rsplit_once('@') {
+            Some((_, host)) => host.to_string(),
+            None => host.to_string(),
+        }
+    }
+}
+
+pub struct IpcConnector {
+    path: PathBuf,
+}
+
+impl IpcConnector {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl Connector for IpcConnector {
+    type Transport = Ipc;
+
+    async fn connect(&self) -> Result<Provider<Ipc>> {
+        Ok(Provider::connect_ipc(&self.path).await?)
+    }
+
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+// Exponential backoff between reconnection attempts
+struct Backoff {
+    initial: Duration,
+    max: Duration,
+    delay: Duration,
+}
+
+impl Backoff {
+    fn new(config: &ReconnectConfig) -> Self {
+        let initial = Duration::from_millis(config.initial_delay_ms);
+        Self {
+            initial,
+            max: Duration::from_millis(config.max_delay_ms),
+            delay: initial,
+        }
+    }
+
+    async fn wait(&mut self) {
+        info!("Reconnecting in {:?}", self.delay);
+        tokio::time::sleep(self.delay).await;
+        self.delay = (self.delay * 2).min(self.max);
+    }
+
+    fn reset(&mut self) {
+        self.delay = self.initial;
+    }
+}
+
+// JSON-RPC client of the provider used for tracing and block fetches.
+// A request that fails in the transport (the node restarted, or ethers' websocket manager
+// gave up reconnecting) opens a new connection, with backoff, and is retried once on it.
+pub struct ReconnectingClient<C: Connector> {
+    connector: Arc<C>,
+    // bumped on every reconnect, so requests that failed together reconnect once
+    current: Mutex<(u64, Arc<Provider<C::Transport>>)>,
+    reconnect: tokio::sync::Mutex<ReconnectState>,
+}
+
+struct ReconnectState {
+    backoff: Backoff,
+    // the last attempt failed, wait before the next one
+    failed: bool,
+}
+
+impl<C: Connector> ReconnectingClient<C> {
+    pub async fn new(connector: Arc<C>, config: &ReconnectConfig) -> Result<Self> {
+        let provider = connector.connect().await?;
+
+        Ok(Self {
+            connector,
+            current: Mutex::new((0, Arc::new(provider))),
+            reconnect: tokio::sync::Mutex::new(ReconnectState {
+                backoff: Backoff::new(config),
+                failed: false,
+            }),
+        })
+    }
+
+    fn current(&self) -> (u64, Arc<Provider<C::Transport>>) {
+        let current = self.current.lock().unwrap();
+        (current.0, current.1.clone())
+    }
+
+    // Connection to retry on, a newer one if another request reconnected in the meantime
+    async fn reconnect(
+        &self,
+        generation: u64,
+    ) -> Result<Arc<Provider<C::Transport>>, ProviderError> {
+        let mut state = self.reconnect.lock().await;
+
+        let (current, provider) = self.current();
+        if current != generation {
+            return Ok(provider);
+        }
+
+        if state.failed {
+            state.backoff.wait().await;
+        }
+
+        match self.connector.connect().await {
+            Ok(provider) => {
+                info!("{}: reconnected", self.connector.name());
+                state.backoff.reset();
+                state.failed = false;
+
+                let provider = Arc::new(provider);
+                *self.current.lock().unwrap() = (generation + 1, provider.clone());
+                Ok(provider)
+            }
+            Err(e) => {
+                state.failed = true;
+                Err(ProviderError::CustomError(format!(
+                    "{}: failed to reconnect: {}",
+                    self.connector.name(),
+                    e
+                )))
+            }
+        }
+    }
+}
+
+impl<C: Connector> fmt::Debug for ReconnectingClient<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReconnectingClient")
+            .field("node", &self.connector.name())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl<C: Connector> JsonRpcClient for ReconnectingClient<C> {
+    type Error = ProviderError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, ProviderError>
+    where
+        T: fmt::Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let params = serde_json::to_value(params)?;
+        let (generation, provider) = self.current();
+
+        match send(&provider, method, &params).await {
+            Err(e) if is_transport_error(&e) => {
+                warn!(
+                    "{}: {} failed, reconnecting: {}",
+                    self.connector.name(),
+                    method,
+                    e
+                );
+                let provider = self.reconnect(generation).await?;
+                send(&provider, method, &params).await
+            }
+            result => result,
+        }
+    }
+}
+
+async fn send<P, R>(
+    provider: &Provider<P>,
+    method: &str,
+    params: &Value,
+) -> Result<R, ProviderError>
+where
+    P: JsonRpcClient,
+    R: DeserializeOwned + Send,
+{
+    JsonRpcClient::request(provider.as_ref(), method, params)
+        .await
+        .map_err(Into::into)
+}
+
+// Neither a JSON-RPC error response nor a response that failed to parse, the connection is gone
+fn is_transport_error(e: &ProviderError) -> bool {
+    matches!(e, ProviderError::JsonRpcClientError(_))
+        && e.as_error_response().is_none()
+        && e.as_serde_error().is_none()
+}
+
+async fn connect<C: Connector>(
+    connector: &C,
+    backoff: &mut Backoff,
+    subscription: Subscription,
+    metrics: &Metrics,
+) -> Provider<C::Transport> {
+    loop {
+        match connector.connect().await {
+            Ok(provider) => return provider,
+            Err(e) => {
+                metrics.record_error(&WatcherError::Rpc(e.to_string()));
+                warn!(
+                    "{} {:?}: failed to connect: {:?}",
+                    connector.name(),
+                    subscription,
+                    e
+                );
+                backoff.wait().await;
+            }
+        }
+    }
+}
+
+// Streams new headers until the event handler is gone, reconnecting whenever the
+// subscription closes or stays silent for longer than idle_timeout_secs.
+// Reorgs are sent as Event::Reorg ahead of the header that caused them.
+// `chain` is shared by the streams of every node, so each header is sent once.
+pub async fn stream_headers<C: Connector>(
+    node: usize,
+    connector: Arc<C>,
+    config: ReconnectConfig,
+    chain: Arc<Mutex<ChainTracker>>,
+    event_sender: EventSender,
+    metrics: Arc<Metrics>,
+) {
+    let subscription = Subscription::NewHeads;
+    let name = connector.name();
+    let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+    let mut backoff = Backoff::new(&config);
+    let mut reconnected = false;
+
+    loop {
+        let provider = connect(connector.as_ref(), &mut backoff, subscription, &metrics).await;
+
+        let stream = match provider.subscribe_blocks().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                let e = WatcherError::rpc(e);
+                metrics.record_error(&e);
+                warn!("{} {:?}: failed to subscribe: {}", name, subscription, e);
+                backoff.wait().await;
+                continue;
+            }
+        };
+        backoff.reset();
+
+        Metrics::incr(&metrics.live_header_streams, 1);
+
+        if reconnected {
+            info!("{} {:?}: resubscribed", name, subscription);
+            if !event_sender
+                .send(Event::Reconnected { node, subscription })
+                .await
+            {
+                return;
+            }
+        }
+        reconnected = true;
+
+        let mut stream = stream.map(NewBlock::try_from);
+
+        loop {
+            match tokio::time::timeout(idle_timeout, stream.next()).await {
+                Ok(Some(Ok(block))) => {
+                    let received = Received {
+                        node,
+                        at: SystemTime::now(),
+                    };
+
+                    // kept across reconnects, so reorgs that happened in between are noticed
+                    let update = chain.lock().unwrap().push(block.clone());
+
+                    match update {
+                        ChainUpdate::Extended => {}
+                        ChainUpdate::Duplicate => continue,
+                        ChainUpdate::Gap => {
+                            warn!(
+                                "{} {:?}: missed headers before #{}",
+                                name, subscription, block.number
+                            );
+                        }
+                        ChainUpdate::Reorg { dropped, new } => {
+                            Metrics::incr(&metrics.reorgs, 1);
+                            Metrics::incr(&metrics.reorged_blocks, dropped.len() as u64);
+
+                            if !event_sender.send(Event::Reorg { dropped, new }).await {
+                                return;
+                            }
+                        }
+                    }
+
+                    let event = Event::NewBlock {
+                        block: Box::new(block),
+                        received,
+                    };
+                    if !event_sender.send(event).await {
+                        return;
+                    }
+                }
+                Ok(Some(Err(e))) => {
+                    metrics.record_error(&e);
+                    error!("{} {:?}: dropping header, {}", name, subscription, e);
+                }
+                Ok(None) => {
+                    warn!("{} {:?}: subscription closed", name, subscription);
+                    break;
+                }
+                Err(_) => {
+                    warn!(
+                        "{} {:?}: nothing received for {:?}",
+                        name, subscription, idle_timeout
+                    );
+                    break;
+                }
+            }
+        }
+        Metrics::decr(&metrics.live_header_streams, 1);
+
+        backoff.wait().await;
+    }
+}
+
+// Same as stream_headers, for pending transactions.
+// Every transaction is sent with the node and time it was received at.
+pub async fn stream_pending_txs<C: Connector>(
+    node: usize,
+    connector: Arc<C>,
+    config: ReconnectConfig,
+    pending_tx_buffer: usize,
+    event_sender: EventSender,
+    metrics: Arc<Metrics>,
+) {
+    let subscription = Subscription::PendingTransactions;
+    let name = connector.name();
+    let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+    let mut backoff = Backoff::new(&config);
+    let mut reconnected = false;
+
+    loop {
+        let provider = connect(connector.as_ref(), &mut backoff, subscription, &metrics).await;
+
+        let mut stream = match provider.subscribe_pending_txs().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                let e = WatcherError::rpc(e);
+                metrics.record_error(&e);
+                warn!("{} {:?}: failed to subscribe: {}", name, subscription, e);
+                backoff.wait().await;
+                continue;
+            }
+        };
+        backoff.reset();
+
+        if reconnected {
+            info!("{} {:?}: resubscribed", name, subscription);
+            if !event_sender
+                .send(Event::Reconnected { node, subscription })
+                .await
+            {
+                return;
+            }
+        }
+        reconnected = true;
+
+        // a transaction is received when its hash arrives, the fetch only adds RPC latency.
+        // Hashes are stamped right away and fetched at most `pending_tx_buffer` at a time.
+        let fetch = |tx_hash: H256, received: Received| {
+            let provider = &provider;
+            async move { (tx_hash, received, provider.get_transaction(tx_hash).await) }
+        };
+        let mut fetching = FuturesUnordered::new();
+        let mut queued = VecDeque::new();
+
+        loop {
+            while fetching.len() < pending_tx_buffer {
+                match queued.pop_front() {
+                    Some((tx_hash, received)) => fetching.push(fetch(tx_hash, received)),
+                    None => break,
+                }
+            }
+
+            tokio::select! {
+                Some((tx_hash, received, fetched)) = fetching.next(), if !fetching.is_empty() => {
+                    match fetched {
+                        Ok(Some(tx)) => {
+                            let event = Event::Transaction {
+                                tx: Box::new(tx),
+                                received,
+                            };
+                            if !event_sender.send(event).await {
+                                return;
+                            }
+                        }
+                        // mostly transactions that were dropped or mined before they could be fetched
+                        Ok(None) => {
+                            let e = WatcherError::PendingTxFetch(format!("not found: {:?}", tx_hash));
+                            metrics.record_error(&e);
+                            debug!("{} {:?}: {}", name, subscription, e);
+                        }
+                        Err(e) => {
+                            let e = WatcherError::PendingTxFetch(e.to_string());
+                            metrics.record_error(&e);
+                            debug!("{} {:?}: {}", name, subscription, e);
+                        }
+                    }
+                }
+                next = tokio::time::timeout(idle_timeout, stream.next()) => match next {
+                    Ok(Some(tx_hash)) => {
+                        let received = Received {
+                            node,
+                            at: SystemTime::now(),
+                        };
+                        queued.push_back((tx_hash, received));
+                    }
+                    Ok(None) => {
+                        warn!("{} {:?}: subscription closed", name, subscription);
+                        break;
+                    }
+                    Err(_) => {
+                        warn!(
+                            "{} {:?}: nothing received for {:?}",
+                            name, subscription, idle_timeout
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+
+        backoff.wait().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::{
+        providers::{JsonRpcError, MockError, MockProvider, MockResponse},
+        types::U64,
+    };
+    use serde_json::value::RawValue;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // A node reached through a MockProvider, answering with the responses pushed to it
+    #[derive(Debug, Clone)]
+    struct FakeTransport(MockProvider);
+
+    #[async_trait]
+    impl JsonRpcClient for FakeTransport {
+        type Error = MockError;
+
+        async fn request<T, R>(&self, method: &str, params: T) -> Result<R, MockError>
+        where
+            T: fmt::Debug + Serialize + Send + Sync,
+            R: DeserializeOwned + Send,
+        {
+            JsonRpcClient::request(&self.0, method, params).await
+        }
+    }
+
+    impl PubsubClient for FakeTransport {
+        type NotificationStream = futures::stream::Empty<Box<RawValue>>;
+
+        fn subscribe<T: Into<ethers::types::U256>>(
+            &self,
+            _id: T,
+        ) -> Result<Self::NotificationStream, MockError> {
+            Ok(futures::stream::empty())
+        }
+
+        fn unsubscribe<T: Into<ethers::types::U256>>(&self, _id: T) -> Result<(), MockError> {
+            Ok(())
+        }
+    }
+
+    // Hands out the given connections in order, None fails to connect
+    struct FakeConnector {
+        connections: Mutex<VecDeque<Option<MockProvider>>>,
+        connects: AtomicUsize,
+    }
+
+    impl FakeConnector {
+        fn new(connections: Vec<Option<MockProvider>>) -> Arc<Self> {
+            Arc::new(Self {
+                connections: Mutex::new(connections.into()),
+                connects: AtomicUsize::new(0),
+            })
+        }
+
+        fn connects(&self) -> usize {
+            self.connects.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl Connector for FakeConnector {
+        type Transport = FakeTransport;
+
+        async fn connect(&self) -> Result<Provider<FakeTransport>> {
+            self.connects.fetch_add(1, Ordering::SeqCst);
+            match self.connections.lock().unwrap().pop_front().flatten() {
+                Some(mock) => Ok(Provider::new(FakeTransport(mock))),
+                None => Err(anyhow::anyhow!("connection refused")),
+            }
+        }
+
+        fn name(&self) -> String {
+            String::from("fake")
+        }
+    }
+
+    fn config() -> ReconnectConfig {
+        ReconnectConfig {
+            initial_delay_ms: 1,
+            max_delay_ms: 5,
+            ..Default::default()
+        }
+    }
+
+    // A connection answering requests with these block numbers in turn, and nothing after them
+    fn node(block_numbers: &[u64]) -> Option<MockProvider> {
+        let mock = MockProvider::new();
+        // the mock answers from the back
+        for number in block_numbers.iter().rev() {
+            mock.push(U64::from(*number)).unwrap();
+        }
+        Some(mock)
+    }
+
+    fn reverted() -> ProviderError {
+        MockError::JsonRpcError(JsonRpcError {
+            code: 3,
+            message: String::from("execution reverted"),
+            data: None,
+        })
+        .into()
+    }
+
+    async fn client(connector: &Arc<FakeConnector>) -> Provider<ReconnectingClient<FakeConnector>> {
+        Provider::new(
+            ReconnectingClient::new(connector.clone(), &config())
+                .await
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn only_failures_without_a_response_are_transport_errors() {
+        assert!(is_transport_error(&MockError::EmptyResponses.into()));
+
+        assert!(!is_transport_error(&reverted()));
+        let invalid = serde_json::from_str::<U64>("{").unwrap_err();
+        assert!(!is_transport_error(&MockError::SerdeJson(invalid).into()));
+        assert!(!is_transport_error(&ProviderError::CustomError(
+            String::from("failed to reconnect")
+        )));
+    }
+
+    #[tokio::test]
+    async fn backoff_doubles_up_to_the_max_and_resets() {
+        let mut backoff = Backoff::new(&config());
+
+        let mut delays = Vec::new();
+        for _ in 0..5 {
+            delays.push(backoff.delay.as_millis());
+            backoff.wait().await;
+        }
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+
+        backoff.reset();
+        assert_eq!(backoff.delay, Duration::from_millis(1));
+    }
+
+    #[tokio::test]
+    async fn failed_request_is_retried_once_on_a_new_connection() {
+        // the first connection answers nothing, as if the node went away
+        let connector = FakeConnector::new(vec![node(&[]), node(&[7, 8]), node(&[9])]);
+        let provider = client(&connector).await;
+
+        assert_eq!(provider.get_block_number().await.unwrap(), U64::from(7));
+        assert_eq!(connector.connects(), 2);
+
+        // later requests stay on the new connection
+        assert_eq!(provider.get_block_number().await.unwrap(), U64::from(8));
+        assert_eq!(connector.connects(), 2);
+    }
+
+    #[tokio::test]
+    async fn retry_on_the_new_connection_is_not_retried_again() {
+        let connector = FakeConnector::new(vec![node(&[]), node(&[]), node(&[7])]);
+        let provider = client(&connector).await;
+
+        assert!(provider.get_block_number().await.is_err());
+        assert_eq!(connector.connects(), 2);
+    }
+
+    #[tokio::test]
+    async fn error_responses_keep_the_connection() {
+        let mock = MockProvider::new();
+        mock.push_response(MockResponse::Error(JsonRpcError {
+            code: 3,
+            message: String::from("execution reverted"),
+            data: None,
+        }));
+        let connector = FakeConnector::new(vec![Some(mock), node(&[7])]);
+        let provider = client(&connector).await;
+
+        assert!(provider.get_block_number().await.is_err());
+        assert_eq!(connector.connects(), 1);
+    }
+
+    #[tokio::test]
+    async fn requests_failing_together_reconnect_once() {
+        let connector = FakeConnector::new(vec![node(&[]), node(&[7, 7]), node(&[8])]);
+        let provider = client(&connector).await;
+
+        let (a, b) = tokio::join!(provider.get_block_number(), provider.get_block_number());
+        assert_eq!(a.unwrap(), U64::from(7));
+        assert_eq!(b.unwrap(), U64::from(7));
+        assert_eq!(connector.connects(), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_reconnect_is_retried_by_the_next_request() {
+        let connector = FakeConnector::new(vec![node(&[]), None, node(&[7])]);
+        let provider = client(&connector).await;
+
+        let e = provider.get_block_number().await.unwrap_err();
+        assert!(e.to_string().contains("failed to reconnect"));
+        assert_eq!(connector.connects(), 2);
+
+        // still on the dead connection, reconnects after waiting out the backoff
+        assert_eq!(provider.get_block_number().await.unwrap(), U64::from(7));
+        assert_eq!(connector.connects(), 3);
+    }
+}
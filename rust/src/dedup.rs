@@ -0,0 +1,174 @@
+use ethers::types::H256;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+// Transaction hashes seen within the last `ttl`, capped at `max_entries` (oldest go first)
+pub struct SeenCache {
+    ttl: Duration,
+    max_entries: usize,
+    // hash -> when it was seen, and the generation of its entry in `order`
+    seen: HashMap<H256, (Instant, u64)>,
+    // insertion order, which is also expiry order. The entry of a removed hash stays behind
+    // until it reaches the front, its generation no longer matches `seen`.
+    order: VecDeque<(H256, u64)>,
+    generation: u64,
+}
+
+impl SeenCache {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+            generation: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    // Returns false if `hash` was already seen within the ttl
+    pub fn insert(&mut self, hash: H256) -> bool {
+        let now = Instant::now();
+        self.expire(now);
+
+        if self.seen.contains_key(&hash) {
+            return false;
+        }
+
+        if self.seen.len() >= self.max_entries {
+            while let Some((oldest, generation)) = self.order.pop_front() {
+                if self.is_current(&oldest, generation) {
+                    self.seen.remove(&oldest);
+                    break;
+                }
+            }
+        }
+
+        self.generation += 1;
+        self.seen.insert(hash, (now, self.generation));
+        self.order.push_back((hash, self.generation));
+
+        // drop the entries of removed hashes before they pile up
+        if self.order.len() > 2 * self.max_entries {
+            let seen = &self.seen;
+            self.order.retain(|(hash, generation)| {
+                seen.get(hash)
+                    .is_some_and(|(_, current)| current == generation)
+            });
+        }
+
+        true
+    }
+
+    // Lets `hash` through again
+    pub fn remove(&mut self, hash: &H256) {
+        self.seen.remove(hash);
+    }
+
+    fn is_current(&self, hash: &H256, generation: u64) -> bool {
+        self.seen
+            .get(hash)
+            .is_some_and(|(_, current)| *current == generation)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((oldest, generation)) = self.order.front() {
+            match self.seen.get(oldest) {
+                Some((seen_at, current)) if current == generation => {
+                    if now.duration_since(*seen_at) < self.ttl {
+                        break;
+                    }
+                    self.seen.remove(oldest);
+                }
+                // removed, or removed and seen again later
+                _ => {}
+            }
+            self.order.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(n: u64) -> H256 {
+        H256::from_low_u64_be(n)
+    }
+
+    #[test]
+    fn hashes_are_seen_once_within_the_ttl() {
+        let mut seen = SeenCache::new(Duration::from_secs(60), 10);
+
+        assert!(seen.insert(hash(1)));
+        assert!(!seen.insert(hash(1)));
+        assert!(seen.insert(hash(2)));
+        assert_eq!(seen.len(), 2);
+    }
+
+    #[test]
+    fn hashes_expire_after_the_ttl() {
+        let mut seen = SeenCache::new(Duration::from_millis(20), 10);
+
+        assert!(seen.insert(hash(1)));
+        std::thread::sleep(Duration::from_millis(30));
+
+        assert!(seen.insert(hash(1)));
+        assert_eq!(seen.len(), 1);
+    }
+
+    #[test]
+    fn oldest_hashes_go_first_at_the_cap() {
+        let mut seen = SeenCache::new(Duration::from_secs(60), 2);
+
+        seen.insert(hash(1));
+        seen.insert(hash(2));
+        seen.insert(hash(3));
+
+        assert_eq!(seen.len(), 2);
+        assert!(!seen.insert(hash(3)));
+        assert!(!seen.insert(hash(2)));
+        assert!(seen.insert(hash(1)));
+    }
+
+    #[test]
+    fn removed_hashes_are_let_through_again() {
+        let mut seen = SeenCache::new(Duration::from_secs(60), 2);
+
+        seen.insert(hash(1));
+        seen.insert(hash(2));
+        seen.remove(&hash(1));
+        assert_eq!(seen.len(), 1);
+
+        assert!(seen.insert(hash(1)));
+        // 2 is the oldest now
+        seen.insert(hash(3));
+        assert!(!seen.insert(hash(1)));
+        assert!(seen.insert(hash(2)));
+    }
+
+    #[test]
+    fn removed_hashes_do_not_pile_up() {
+        let mut seen = SeenCache::new(Duration::from_secs(60), 2);
+
+        for _ in 0..10 {
+            seen.insert(hash(1));
+            seen.remove(&hash(1));
+        }
+        seen.insert(hash(2));
+
+        assert_eq!(seen.len(), 1);
+        assert!(seen.order.len() <= 4);
+        assert!(!seen.insert(hash(2)));
+        assert!(seen.insert(hash(1)));
+    }
+}
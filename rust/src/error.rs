@@ -0,0 +1,81 @@
+use ethers::{
+    providers::MiddlewareError,
+    types::{Address, H256},
+};
+use std::time::Duration;
+use thiserror::Error;
+
+// JSON-RPC "method not found", returned by nodes without the trace/debug namespaces
+const METHOD_NOT_FOUND: i64 = -32601;
+
+#[derive(Debug, Error)]
+pub enum WatcherError {
+    #[error("tracing is not supported by the node: {0}")]
+    TraceUnsupported(String),
+    #[error("state diff does not exist")]
+    MissingStateDiff,
+    #[error("no target storage: {0:?}")]
+    MissingTargetStorage(Address),
+    #[error("invalid trace: {0}")]
+    InvalidTrace(String),
+    #[error("rpc request timed out after {0:?}")]
+    RpcTimeout(Duration),
+    #[error("rpc error: {0}")]
+    Rpc(String),
+    #[error("failed to fetch pending transaction: {0}")]
+    PendingTxFetch(String),
+    #[error("invalid header: {0}")]
+    InvalidHeader(String),
+    #[error("transaction not found: {0:?}")]
+    TransactionNotFound(H256),
+    #[error("transaction is not mined: {0:?}")]
+    TransactionNotMined(H256),
+    #[error("block not found: {0}")]
+    BlockNotFound(u64),
+    #[error("simulation failed: {0}")]
+    Simulation(String),
+    #[error("hardfork is not supported by the simulation: {0}")]
+    UnsupportedHardfork(String),
+    #[error("failed to write detections: {0}")]
+    Output(String),
+    #[error("failed to record event: {0}")]
+    Recording(String),
+    #[error("task failed: {0}")]
+    TaskFailed(String),
+    #[error("preceding transaction {0:?} failed: {1}")]
+    PrecedingTxFailed(H256, String),
+}
+
+impl WatcherError {
+    pub fn rpc<E: MiddlewareError>(e: E) -> Self {
+        match e.as_error_response() {
+            Some(response) if response.code == METHOD_NOT_FOUND => {
+                WatcherError::TraceUnsupported(response.message.clone())
+            }
+            _ => WatcherError::Rpc(e.to_string()),
+        }
+    }
+
+    // Key the error is counted under in Metrics
+    pub fn category(&self) -> &'static str {
+        match self {
+            WatcherError::TraceUnsupported(_) => "trace_unsupported",
+            WatcherError::MissingStateDiff => "missing_state_diff",
+            WatcherError::MissingTargetStorage(_) => "missing_target_storage",
+            WatcherError::InvalidTrace(_) => "invalid_trace",
+            WatcherError::RpcTimeout(_) => "rpc_timeout",
+            WatcherError::Rpc(_) => "rpc",
+            WatcherError::PendingTxFetch(_) => "pending_tx_fetch",
+            WatcherError::InvalidHeader(_) => "invalid_header",
+            WatcherError::TransactionNotFound(_) => "transaction_not_found",
+            WatcherError::TransactionNotMined(_) => "transaction_not_mined",
+            WatcherError::BlockNotFound(_) => "block_not_found",
+            WatcherError::Simulation(_) => "simulation",
+            WatcherError::UnsupportedHardfork(_) => "unsupported_hardfork",
+            WatcherError::Output(_) => "output",
+            WatcherError::Recording(_) => "recording",
+            WatcherError::TaskFailed(_) => "task_failed",
+            WatcherError::PrecedingTxFailed(..) => "preceding_tx_failed",
+        }
+    }
+}
@@ -1,2 +1,18 @@
+pub mod backend;
+pub mod backtest;
+pub mod chain;
+pub mod channel;
+pub mod config;
+pub mod connection;
+pub mod dedup;
+pub mod error;
+pub mod mempool;
+pub mod metrics;
+pub mod pools;
+pub mod propagation;
+pub mod recording;
+pub mod scoring;
+pub mod simulation;
+pub mod slots;
 pub mod trace;
 pub mod utils;
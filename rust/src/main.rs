@@ -1,10 +1,129 @@
-use anyhow::{Ok, Result};
+use anyhow::{anyhow, Ok, Result};
+use cfmms::pool::Pool;
+use clap::{Parser, Subcommand};
+use dashmap::DashMap;
+use ethers::{
+    providers::{Middleware, Provider},
+    types::{Address, H160, H256},
+};
 use fern::colors::{Color, ColoredLevelConfig};
-use log::LevelFilter;
+use log::{info, LevelFilter};
+use std::{path::PathBuf, str::FromStr, sync::Arc};
 
-use revm_playground::trace::mempool_watching;
+use revm_playground::{
+    backend::{new_backend, TraceBackend},
+    backtest::{load_detections, Backtest},
+    config::WatcherConfig,
+    connection::{Connector, IpcConnector, ReconnectingClient, WsConnector},
+    metrics::Metrics,
+    pools::sync_pools,
+    recording::{recorded_tx_hashes, recording_files},
+    trace::{
+        discover_targets, mempool_watching, playback, replay_blocks, trace_transaction,
+        OutputFormat, TargetToken,
+    },
+};
+
+#[derive(Parser)]
+#[command(about = "Watch the mempool for pending swaps touching target tokens")]
+struct Cli {
+    #[arg(long, env = "CONFIG_PATH", default_value = "config.toml")]
+    config: PathBuf,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Build or refresh the cfmms checkpoint
+    Sync {
+        /// Ignore the existing checkpoint and sync every pool from the factories' creation blocks
+        #[arg(long)]
+        fresh: bool,
+    },
+    /// Watch pending transactions live
+    Watch {
+        /// Target token to watch, replaces the tokens in the config (repeatable)
+        #[arg(long = "token")]
+        tokens: Vec<Address>,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Log)]
+        output: OutputFormat,
+    },
+    /// Feed recorded events through the watcher, from a recording file or directory
+    Playback {
+        path: PathBuf,
+        /// 1 plays at the recorded pace, 10 ten times faster
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+        #[arg(long = "token")]
+        tokens: Vec<Address>,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Log)]
+        output: OutputFormat,
+    },
+    /// Run the state diff analysis on a single transaction
+    Trace {
+        tx_hash: H256,
+        #[arg(long = "token")]
+        tokens: Vec<Address>,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Log)]
+        output: OutputFormat,
+    },
+    /// Run the state diff analysis over historical blocks, e.g. 18000000..18000010
+    Replay {
+        blocks: BlockRange,
+        #[arg(long = "token")]
+        tokens: Vec<Address>,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Log)]
+        output: OutputFormat,
+    },
+    /// Score detections from `watch --output json` against what the mined blocks actually did
+    Backtest {
+        blocks: BlockRange,
+        /// Detections file, one JSON object per line
+        #[arg(long)]
+        detections: PathBuf,
+        /// Recording of the same period, pool touches of transactions it never saw pending
+        /// are left out of the recall
+        #[arg(long)]
+        recording: Option<PathBuf>,
+        #[arg(long = "token")]
+        tokens: Vec<Address>,
+        /// With json, the scores are printed to stdout
+        #[arg(long, value_enum, default_value_t = OutputFormat::Log)]
+        output: OutputFormat,
+    },
+}
+
+// Inclusive range of block numbers: "FROM..TO" or a single "NUMBER"
+#[derive(Debug, Clone, Copy)]
+struct BlockRange {
+    from: u64,
+    to: u64,
+}
+
+impl FromStr for BlockRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (from, to) = match s.split_once("..") {
+            Some((from, to)) => (from.trim().parse()?, to.trim().parse()?),
+            None => {
+                let number = s.trim().parse()?;
+                (number, number)
+            }
+        };
+
+        if from > to {
+            return Err(anyhow!("invalid block range {}: {} > {}", s, from, to));
+        }
+
+        Ok(BlockRange { from, to })
+    }
+}
 
 // Just some logger setup to prettify console prints
+// Logs go to stderr, so detections printed as JSON on stdout can be piped
 pub fn setup_logger() -> Result<()> {
     let colors = ColoredLevelConfig {
         trace: Color::Cyan,
@@ -12,7 +131,6 @@ pub fn setup_logger() -> Result<()> {
         info: Color::Green,
         warn: Color::Red,
         error: Color::BrightRed,
-        ..ColoredLevelConfig::new()
     };
 
     fern::Dispatch::new()
@@ -24,7 +142,7 @@ pub fn setup_logger() -> Result<()> {
                 message
             ))
         })
-        .chain(std::io::stdout())
+        .chain(std::io::stderr())
         .level(log::LevelFilter::Error)
         .level_for("revm_playground", LevelFilter::Info)
         .apply()?;
@@ -32,13 +150,195 @@ pub fn setup_logger() -> Result<()> {
     Ok(())
 }
 
+fn with_tokens(mut config: WatcherConfig, tokens: Vec<Address>) -> WatcherConfig {
+    if !tokens.is_empty() {
+        config.target_tokens = tokens;
+    }
+    config
+}
+
+// Pools, target tokens and backend of the commands analysing mined transactions
+struct Analysis {
+    pools: DashMap<H160, Pool>,
+    targets: Vec<TargetToken>,
+    backend: Arc<dyn TraceBackend>,
+    metrics: Arc<Metrics>,
+}
+
+impl Analysis {
+    async fn setup<M: Middleware + 'static>(
+        provider: Arc<M>,
+        config: &WatcherConfig,
+    ) -> Result<Self>
+    where
+        M::Error: 'static,
+    {
+        let pools = sync_pools(
+            provider.clone(),
+            &config.dex_specs(),
+            &config.checkpoint.path,
+            config.checkpoint.step,
+        )
+        .await?;
+        let targets = discover_targets(
+            provider.clone(),
+            &config.target_tokens,
+            &config.checkpoint.path,
+        )
+        .await?;
+
+        let metrics = Arc::new(Metrics::default());
+        let backend = new_backend(config.tracing.backend, provider, metrics.clone());
+
+        Ok(Self {
+            pools,
+            targets,
+            backend,
+            metrics,
+        })
+    }
+}
+
+// The first connector is used for everything but streaming
+async fn run<C: Connector>(
+    command: Command,
+    config: WatcherConfig,
+    connectors: Vec<C>,
+) -> Result<()> {
+    let connectors: Vec<_> = connectors.into_iter().map(Arc::new).collect();
+    // reconnects on its own, like the subscriptions
+    let client = ReconnectingClient::new(connectors[0].clone(), &config.rpc.reconnect).await?;
+    let provider = Arc::new(Provider::new(client));
+
+    match command {
+        Command::Sync { fresh } => {
+            if fresh && std::path::Path::new(&config.checkpoint.path).exists() {
+                std::fs::remove_file(&config.checkpoint.path)?;
+            }
+
+            sync_pools(
+                provider,
+                &config.dex_specs(),
+                &config.checkpoint.path,
+                config.checkpoint.step,
+            )
+            .await?;
+        }
+        Command::Watch { tokens, output } => {
+            mempool_watching(provider, connectors, with_tokens(config, tokens), output).await?;
+        }
+        Command::Playback {
+            path,
+            speed,
+            tokens,
+            output,
+        } => {
+            if !(speed > 0.0 && speed.is_finite()) {
+                return Err(anyhow!("invalid speed {}: must be greater than 0", speed));
+            }
+            let files = recording_files(&path)?;
+            playback(provider, with_tokens(config, tokens), files, speed, output).await?;
+        }
+        Command::Trace {
+            tx_hash,
+            tokens,
+            output,
+        } => {
+            let analysis = Analysis::setup(provider.clone(), &with_tokens(config, tokens)).await?;
+            let touched = trace_transaction(
+                provider,
+                analysis.backend.as_ref(),
+                tx_hash,
+                &analysis.pools,
+                &analysis.targets,
+            )
+            .await?;
+            info!("Tx #{:?} touched {} pools", tx_hash, touched.len());
+            output.emit(&touched)?;
+        }
+        Command::Replay {
+            blocks,
+            tokens,
+            output,
+        } => {
+            let analysis = Analysis::setup(provider.clone(), &with_tokens(config, tokens)).await?;
+            let replayed = replay_blocks(
+                provider,
+                analysis.backend.as_ref(),
+                blocks.from..=blocks.to,
+                &analysis.pools,
+                &analysis.targets,
+                output,
+                &analysis.metrics,
+            )
+            .await?;
+            info!(
+                "Blocks #{}..#{}: {} pool touches",
+                blocks.from,
+                blocks.to,
+                replayed.detections.len()
+            );
+            analysis.metrics.report();
+        }
+        Command::Backtest {
+            blocks,
+            detections,
+            recording,
+            tokens,
+            output,
+        } => {
+            let predicted = load_detections(&detections)?;
+            let seen = match recording {
+                Some(path) => Some(recorded_tx_hashes(&recording_files(&path)?)?),
+                None => None,
+            };
+
+            let analysis = Analysis::setup(provider.clone(), &with_tokens(config, tokens)).await?;
+            let replayed = replay_blocks(
+                provider,
+                analysis.backend.as_ref(),
+                blocks.from..=blocks.to,
+                &analysis.pools,
+                &analysis.targets,
+                OutputFormat::Log,
+                &analysis.metrics,
+            )
+            .await?;
+
+            let backtest = Backtest::evaluate(
+                &predicted,
+                &replayed.detections,
+                &replayed.mined,
+                seen.as_ref(),
+            );
+            analysis.metrics.report();
+            backtest.report();
+            if let OutputFormat::Json = output {
+                println!("{}", serde_json::to_string(&backtest)?);
+            }
+        }
+    }
+
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv::dotenv().ok();
     setup_logger()?;
 
-    let weth = String::from("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2");
-    mempool_watching(weth).await?;
+    let cli = Cli::parse();
+    let config = WatcherConfig::load(&cli.config)?;
 
-    Ok(())
+    match config.rpc.ipc_path.clone() {
+        Some(ipc_path) => run(cli.command, config, vec![IpcConnector::new(ipc_path)]).await,
+        None => {
+            let connectors = config
+                .wss_urls()?
+                .into_iter()
+                .map(WsConnector::new)
+                .collect();
+            run(cli.command, config, connectors).await
+        }
+    }
 }
@@ -0,0 +1,484 @@
+use ethers::types::{Address, Transaction, H160, H256, U256, U64};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+};
+
+use crate::trace::TouchedPool;
+use crate::utils::TxFees;
+
+#[derive(Debug, Clone)]
+pub struct PendingTx {
+    pub tx: Transaction,
+    // block the transaction was first seen after
+    pub seen_at: U64,
+    // hashes of the transactions it replaced, oldest first
+    pub replaced: Vec<H256>,
+    // pools whose <target_token> balances it changes, filled once it is traced
+    pub detections: Vec<TouchedPool>,
+}
+
+#[derive(Debug, Clone)]
+pub enum MempoolUpdate {
+    New,
+    // already tracked under the same hash
+    Duplicate,
+    // same sender and nonce, different transaction
+    Replaced {
+        old: Box<Transaction>,
+        // a transfer to self without calldata, the usual way to cancel a transaction
+        cancellation: bool,
+        // the replacement pays a higher max fee
+        fee_bump: bool,
+    },
+}
+
+// Pending transactions keyed by (sender, nonce), only one of them can be mined
+#[derive(Debug, Default)]
+pub struct Mempool {
+    txs: HashMap<(Address, U256), PendingTx>,
+    by_hash: HashMap<H256, (Address, U256)>,
+    by_pool: HashMap<H160, HashSet<H256>>,
+    // block hash -> its number and the transactions its mining evicted, restored if it is orphaned
+    evicted: HashMap<H256, (U64, Vec<PendingTx>)>,
+}
+
+impl Mempool {
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    pub fn get(&self, tx_hash: &H256) -> Option<&PendingTx> {
+        self.by_hash.get(tx_hash).and_then(|key| self.txs.get(key))
+    }
+
+    pub fn insert(&mut self, tx: Transaction, block_number: U64) -> MempoolUpdate {
+        let key = (tx.from, tx.nonce);
+
+        let (update, replaced) = match self.txs.remove(&key) {
+            None => (MempoolUpdate::New, Vec::new()),
+            Some(old) if old.tx.hash == tx.hash => {
+                self.txs.insert(key, old);
+                return MempoolUpdate::Duplicate;
+            }
+            Some(old) => {
+                self.forget(&old);
+
+                let fee_bump =
+                    TxFees::of(&tx).max_fee_per_gas > TxFees::of(&old.tx).max_fee_per_gas;
+                let cancellation = tx.to == Some(tx.from) && tx.input.is_empty();
+
+                let mut replaced = old.replaced;
+                replaced.push(old.tx.hash);
+
+                (
+                    MempoolUpdate::Replaced {
+                        old: Box::new(old.tx),
+                        cancellation,
+                        fee_bump,
+                    },
+                    replaced,
+                )
+            }
+        };
+
+        self.by_hash.insert(tx.hash, key);
+        self.txs.insert(
+            key,
+            PendingTx {
+                tx,
+                seen_at: block_number,
+                replaced,
+                detections: Vec::new(),
+            },
+        );
+
+        update
+    }
+
+    // Attaches trace results, ignored if the transaction was replaced or mined in the meantime
+    pub fn record(&mut self, tx_hash: H256, detections: &[TouchedPool]) {
+        let Some(key) = self.by_hash.get(&tx_hash) else {
+            return;
+        };
+        let Some(pending) = self.txs.get_mut(key) else {
+            return;
+        };
+
+        for detection in detections {
+            self.by_pool
+                .entry(detection.pool)
+                .or_default()
+                .insert(tx_hash);
+        }
+        pending.detections = detections.to_vec();
+    }
+
+    // Pending transactions that swap in `pool`
+    pub fn pending_swaps(&self, pool: H160) -> Vec<&PendingTx> {
+        self.by_pool
+            .get(&pool)
+            .map(|hashes| hashes.iter().filter_map(|hash| self.get(hash)).collect())
+            .unwrap_or_default()
+    }
+
+    // Pending transactions a builder would put ahead of `tx` that swap in any of `pools`: the ones
+    // paying a higher tip at `base_fee`, and the sender's own with a lower nonce. At most `limit`,
+    // every sender's transactions in nonce order.
+    pub fn ahead(
+        &self,
+        tx: &Transaction,
+        pools: &[H160],
+        base_fee: U256,
+        limit: usize,
+    ) -> Vec<Transaction> {
+        let tip = TxFees::of(tx).priority_fee(base_fee).unwrap_or_default();
+
+        let hashes: HashSet<&H256> = pools
+            .iter()
+            .filter_map(|pool| self.by_pool.get(pool))
+            .flatten()
+            .collect();
+
+        let mut candidates: Vec<(U256, &Transaction)> = hashes
+            .into_iter()
+            .filter_map(|hash| self.get(hash))
+            .map(|pending| &pending.tx)
+            .filter(|other| other.hash != tx.hash)
+            .filter_map(|other| {
+                let other_tip = TxFees::of(other).priority_fee(base_fee)?;
+                let ahead = match other.from == tx.from {
+                    true => other.nonce < tx.nonce,
+                    false => other_tip > tip,
+                };
+                ahead.then_some((other_tip, other))
+            })
+            .collect();
+        // highest tip first, ties by hash so the order is stable
+        candidates.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.hash.cmp(&b.1.hash)));
+
+        // a sender's transactions keep their places in the tip order, but fill them by nonce
+        let mut by_sender: HashMap<Address, Vec<&Transaction>> = HashMap::new();
+        for (_, other) in &candidates {
+            by_sender.entry(other.from).or_default().push(other);
+        }
+        for txs in by_sender.values_mut() {
+            // highest nonce first, they are popped from the end
+            txs.sort_by_key(|tx| Reverse(tx.nonce));
+        }
+
+        candidates
+            .iter()
+            .take(limit)
+            .filter_map(|(_, other)| by_sender.get_mut(&other.from)?.pop())
+            .cloned()
+            .collect()
+    }
+
+    // Drops the mined transactions, and everything of their senders with a lower or equal nonce
+    pub fn evict_mined(
+        &mut self,
+        block_number: U64,
+        block_hash: H256,
+        mined: &[Transaction],
+    ) -> usize {
+        let mut nonces: HashMap<Address, U256> = HashMap::new();
+        for tx in mined {
+            let nonce = nonces.entry(tx.from).or_insert(tx.nonce);
+            *nonce = (*nonce).max(tx.nonce);
+        }
+
+        let evicted = self.evict(|pending| {
+            nonces
+                .get(&pending.tx.from)
+                .is_some_and(|nonce| pending.tx.nonce <= *nonce)
+        });
+        let count = evicted.len();
+        self.evicted.insert(block_hash, (block_number, evicted));
+
+        count
+    }
+
+    // Puts back what the orphaned blocks evicted, unless the sender's nonce was taken since.
+    // Returns the hashes of the restored transactions.
+    pub fn restore(&mut self, block_hashes: &[H256]) -> Vec<H256> {
+        let mut restored = Vec::new();
+
+        for block_hash in block_hashes {
+            let Some((_, evicted)) = self.evicted.remove(block_hash) else {
+                continue;
+            };
+
+            for pending in evicted {
+                let key = (pending.tx.from, pending.tx.nonce);
+                if self.txs.contains_key(&key) {
+                    continue;
+                }
+
+                self.by_hash.insert(pending.tx.hash, key);
+                for detection in &pending.detections {
+                    self.by_pool
+                        .entry(detection.pool)
+                        .or_default()
+                        .insert(pending.tx.hash);
+                }
+                restored.push(pending.tx.hash);
+                self.txs.insert(key, pending);
+            }
+        }
+
+        restored
+    }
+
+    // Drops every trace result, e.g. when the state they ran against was orphaned.
+    // Returns the hashes of the transactions that had any.
+    pub fn clear_detections(&mut self) -> Vec<H256> {
+        self.by_pool.clear();
+
+        self.txs
+            .values_mut()
+            .filter(|pending| !pending.detections.is_empty())
+            .map(|pending| {
+                pending.detections.clear();
+                pending.tx.hash
+            })
+            .collect()
+    }
+
+    // Drops transactions first seen before `block_number`, nodes evict them sooner or later
+    pub fn expire(&mut self, block_number: U64) -> usize {
+        self.evicted
+            .retain(|_, (number, _)| *number >= block_number);
+        self.evict(|pending| pending.seen_at < block_number).len()
+    }
+
+    fn evict(&mut self, f: impl Fn(&PendingTx) -> bool) -> Vec<PendingTx> {
+        let keys: Vec<_> = self
+            .txs
+            .iter()
+            .filter(|(_, pending)| f(pending))
+            .map(|(key, _)| *key)
+            .collect();
+
+        let mut evicted = Vec::with_capacity(keys.len());
+        for key in &keys {
+            if let Some(pending) = self.txs.remove(key) {
+                self.forget(&pending);
+                evicted.push(pending);
+            }
+        }
+
+        evicted
+    }
+
+    fn forget(&mut self, pending: &PendingTx) {
+        self.by_hash.remove(&pending.tx.hash);
+
+        for detection in &pending.detections {
+            if let Some(hashes) = self.by_pool.get_mut(&detection.pool) {
+                hashes.remove(&pending.tx.hash);
+                if hashes.is_empty() {
+                    self.by_pool.remove(&detection.pool);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::SwapDirection;
+    use ethers::types::I256;
+
+    fn tx(from: u64, nonce: u64, hash: u64) -> Transaction {
+        Transaction {
+            from: Address::from_low_u64_be(from),
+            nonce: U256::from(nonce),
+            hash: H256::from_low_u64_be(hash),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn orphaned_blocks_give_back_what_they_evicted() {
+        let mut mempool = Mempool::default();
+        mempool.insert(tx(1, 0, 10), U64::from(1));
+        mempool.insert(tx(1, 1, 11), U64::from(1));
+        mempool.insert(tx(2, 0, 20), U64::from(1));
+
+        let orphaned = H256::from_low_u64_be(100);
+        assert_eq!(
+            mempool.evict_mined(U64::from(2), orphaned, &[tx(1, 1, 11)]),
+            2
+        );
+        assert_eq!(mempool.len(), 1);
+
+        // the sender's nonce 0 was taken by another transaction in the meantime
+        mempool.insert(tx(1, 0, 12), U64::from(2));
+
+        assert_eq!(
+            mempool.restore(&[orphaned]),
+            vec![H256::from_low_u64_be(11)]
+        );
+        assert!(mempool.get(&H256::from_low_u64_be(11)).is_some());
+        assert!(mempool.get(&H256::from_low_u64_be(10)).is_none());
+        assert!(mempool.restore(&[orphaned]).is_empty());
+    }
+
+    #[test]
+    fn expired_evictions_are_not_restored() {
+        let mut mempool = Mempool::default();
+        mempool.insert(tx(1, 0, 10), U64::from(1));
+
+        let orphaned = H256::from_low_u64_be(100);
+        mempool.evict_mined(U64::from(2), orphaned, &[tx(1, 0, 10)]);
+        mempool.expire(U64::from(3));
+
+        assert!(mempool.restore(&[orphaned]).is_empty());
+        assert!(mempool.is_empty());
+    }
+
+    fn priced(from: u64, nonce: u64, hash: u64, gas_price: u64) -> Transaction {
+        Transaction {
+            gas_price: Some(U256::from(gas_price)),
+            ..tx(from, nonce, hash)
+        }
+    }
+
+    fn swap(tx: &Transaction, pool: u64) -> TouchedPool {
+        TouchedPool {
+            tx_hash: tx.hash,
+            pool: H160::from_low_u64_be(pool),
+            token: H160::zero(),
+            balance_before: U256::zero(),
+            balance_after: U256::zero(),
+            direction: SwapDirection::TargetToToken,
+            amount: I256::zero(),
+            score: None,
+            first_seen: Vec::new(),
+        }
+    }
+
+    fn hashes(txs: &[Transaction]) -> Vec<u64> {
+        txs.iter().map(|tx| tx.hash.to_low_u64_be()).collect()
+    }
+
+    #[test]
+    fn ahead_are_higher_tips_in_the_same_pools() {
+        let mut mempool = Mempool::default();
+        let pending = [
+            (priced(1, 0, 10, 30), 1),
+            (priced(2, 0, 20, 50), 1),
+            (priced(3, 0, 30, 40), 2),
+            // lower tip
+            (priced(4, 0, 40, 15), 1),
+            // other pool
+            (priced(5, 0, 50, 90), 3),
+        ];
+        for (tx, pool) in &pending {
+            mempool.insert(tx.clone(), U64::from(1));
+            mempool.record(tx.hash, &[swap(tx, *pool)]);
+        }
+
+        let tx = priced(9, 0, 90, 20);
+        let pools = [H160::from_low_u64_be(1), H160::from_low_u64_be(2)];
+
+        let ahead = mempool.ahead(&tx, &pools, U256::from(10), 10);
+        assert_eq!(hashes(&ahead), vec![20, 30, 10]);
+
+        let ahead = mempool.ahead(&tx, &pools, U256::from(10), 2);
+        assert_eq!(hashes(&ahead), vec![20, 30]);
+    }
+
+    #[test]
+    fn ahead_keeps_senders_in_nonce_order() {
+        let mut mempool = Mempool::default();
+        let pending = [
+            // the higher nonce pays more, it can't go first anyway
+            priced(1, 0, 10, 30),
+            priced(1, 1, 11, 60),
+            priced(2, 0, 20, 40),
+            // the sender's own lower nonce goes ahead whatever it pays
+            priced(9, 0, 90, 12),
+            // and its higher nonce never does
+            priced(9, 2, 92, 80),
+        ];
+        for tx in &pending {
+            mempool.insert(tx.clone(), U64::from(1));
+            mempool.record(tx.hash, &[swap(tx, 1)]);
+        }
+
+        let tx = priced(9, 1, 91, 20);
+        let ahead = mempool.ahead(&tx, &[H160::from_low_u64_be(1)], U256::from(10), 10);
+        assert_eq!(hashes(&ahead), vec![10, 20, 11, 90]);
+    }
+
+    #[test]
+    fn replaced_transactions_are_not_ahead() {
+        let mut mempool = Mempool::default();
+        let old = priced(1, 0, 10, 50);
+        mempool.insert(old.clone(), U64::from(1));
+        mempool.record(old.hash, &[swap(&old, 1)]);
+        // not traced yet
+        mempool.insert(priced(1, 0, 11, 60), U64::from(1));
+
+        let tx = priced(9, 0, 90, 20);
+        assert!(mempool
+            .ahead(&tx, &[H160::from_low_u64_be(1)], U256::from(10), 10)
+            .is_empty());
+    }
+
+    #[test]
+    fn pending_swaps_follow_replacements_evictions_and_reorgs() {
+        let pool = H160::from_low_u64_be(1);
+        let swaps = |mempool: &Mempool| {
+            let mut swaps: Vec<_> = mempool
+                .pending_swaps(pool)
+                .iter()
+                .map(|pending| pending.tx.hash.to_low_u64_be())
+                .collect();
+            swaps.sort();
+            swaps
+        };
+
+        let mut mempool = Mempool::default();
+        for tx in [tx(1, 0, 10), tx(2, 0, 20), tx(3, 0, 30)] {
+            mempool.insert(tx.clone(), U64::from(1));
+            mempool.record(tx.hash, &[swap(&tx, 1)]);
+        }
+        // swaps in another pool
+        let other = tx(4, 0, 40);
+        mempool.insert(other.clone(), U64::from(1));
+        mempool.record(other.hash, &[swap(&other, 2)]);
+        assert_eq!(swaps(&mempool), vec![10, 20, 30]);
+        assert!(mempool.pending_swaps(H160::from_low_u64_be(3)).is_empty());
+
+        // the replacement isn't traced yet
+        mempool.insert(tx(1, 0, 11), U64::from(1));
+        assert_eq!(swaps(&mempool), vec![20, 30]);
+
+        let mined = H256::from_low_u64_be(100);
+        mempool.evict_mined(U64::from(2), mined, &[tx(2, 0, 20)]);
+        assert_eq!(swaps(&mempool), vec![30]);
+
+        // an orphaned block brings its swaps back, until the detections are cleared
+        mempool.restore(&[mined]);
+        assert_eq!(swaps(&mempool), vec![20, 30]);
+        let mut cleared = mempool.clear_detections();
+        cleared.sort();
+        assert_eq!(
+            cleared,
+            vec![
+                H256::from_low_u64_be(20),
+                H256::from_low_u64_be(30),
+                H256::from_low_u64_be(40)
+            ]
+        );
+        assert!(swaps(&mempool).is_empty());
+    }
+}
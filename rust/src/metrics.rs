@@ -0,0 +1,64 @@
+use dashmap::DashMap;
+use log::info;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::error::WatcherError;
+
+// Counters shared by the tasks in the mempool pipeline
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // events the handler never saw because the broadcast channel overflowed
+    pub lagged_events: AtomicU64,
+    // times the receiver skipped the backlog by resubscribing
+    pub resubscribes: AtomicU64,
+    // times a producer waited for the handler to drain the channel
+    pub throttled_sends: AtomicU64,
+    // chain reorganizations seen on newHeads, and the blocks they orphaned
+    pub reorgs: AtomicU64,
+    pub reorged_blocks: AtomicU64,
+    // pending transactions skipped because their hash was traced recently
+    pub duplicate_txs: AtomicU64,
+    // header subscriptions currently open, one per node at most
+    pub live_header_streams: AtomicU64,
+    // failures per WatcherError::category
+    pub errors: DashMap<&'static str, u64>,
+}
+
+impl Metrics {
+    pub fn incr(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn decr(counter: &AtomicU64, n: u64) {
+        counter.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    pub fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+
+    pub fn record_error(&self, e: &WatcherError) {
+        *self.errors.entry(e.category()).or_insert(0) += 1;
+    }
+
+    pub fn report(&self) {
+        let mut errors: Vec<_> = self
+            .errors
+            .iter()
+            .map(|entry| format!("{}={}", entry.key(), entry.value()))
+            .collect();
+        errors.sort();
+
+        info!(
+            "Metrics: lagged_events={} resubscribes={} throttled_sends={} reorgs={} reorged_blocks={} duplicate_txs={} live_header_streams={} errors=[{}]",
+            Self::get(&self.lagged_events),
+            Self::get(&self.resubscribes),
+            Self::get(&self.throttled_sends),
+            Self::get(&self.reorgs),
+            Self::get(&self.reorged_blocks),
+            Self::get(&self.duplicate_txs),
+            Self::get(&self.live_header_streams),
+            errors.join(" "),
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use anyhow::Result;
+use cfmms::{
+    checkpoint::sync_pools_from_checkpoint,
+    dex::{Dex, DexVariant},
+    pool::Pool,
+    sync::sync_pairs,
+};
+use dashmap::DashMap;
+use ethers::{providers::Middleware, types::H160};
+use log::info;
+use std::{path::Path, sync::Arc};
+
+// A factory whose pools are synced with cfmms-rs
+#[derive(Debug, Clone)]
+pub struct DexSpec {
+    pub name: String,
+    pub factory: H160,
+    pub variant: DexVariant,
+    pub creation_block: u64,
+    // only used by V2-style pools, V3 pools read their fee from the pool itself
+    pub fee: u64,
+}
+
+impl DexSpec {
+    pub fn dex(&self) -> Dex {
+        Dex::new(
+            self.factory,
+            self.variant,
+            self.creation_block,
+            Some(self.fee),
+        )
+    }
+}
+
+pub async fn sync_pools<M: Middleware + 'static>(
+    provider: Arc<M>,
+    dexes: &[DexSpec],
+    checkpoint_path: &str,
+    step: usize,
+) -> Result<DashMap<H160, Pool>>
+where
+    M::Error: 'static,
+{
+    let dexes: Vec<Dex> = dexes.iter().map(|dex| dex.dex()).collect();
+
+    let mut pools_vec = None;
+
+    if Path::new(checkpoint_path).exists() {
+        let (synced_dexes, synced_pools) =
+            sync_pools_from_checkpoint(checkpoint_path, step, provider.clone()).await?;
+
+        // a checkpoint only knows the factories it was created with
+        let synced_factories: Vec<H160> = synced_dexes
+            .iter()
+            .map(|dex| dex.factory_address())
+            .collect();
+        let missing = dexes
+            .iter()
+            .any(|dex| !synced_factories.contains(&dex.factory_address()));
+
+        if missing {
+            info!(
+                "New factories configured, resyncing checkpoint: {}",
+                checkpoint_path
+            );
+        } else {
+            pools_vec = Some(synced_pools);
+        }
+    }
+
+    let pools_vec = match pools_vec {
+        Some(pools_vec) => pools_vec,
+        None => sync_pairs(dexes, provider.clone(), Some(checkpoint_path)).await?,
+    };
+
+    let pools = DashMap::new();
+    let (mut v2, mut v3) = (0, 0);
+
+    for pool in pools_vec {
+        match pool {
+            Pool::UniswapV2(_) => v2 += 1,
+            Pool::UniswapV3(_) => v3 += 1,
+        }
+        pools.insert(pool.address(), pool);
+    }
+
+    info!("Pools synced: {} (V2: {}, V3: {})", pools.len(), v2, v3);
+
+    Ok(pools)
+}
@@ -0,0 +1,271 @@
+use ethers::types::H256;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, SystemTime},
+};
+
+use crate::trace::Received;
+use crate::utils::unix_micros;
+
+// A node that delivered a pending transaction, and when
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sighting {
+    pub node: String,
+    // microseconds since the unix epoch
+    pub at: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NodeStats {
+    // pending transactions received from the node
+    pub seen: u64,
+    // ... that no other node delivered earlier
+    pub first: u64,
+    // how long after the first node it delivered the others
+    pub total_delay: Duration,
+}
+
+impl NodeStats {
+    pub fn average_delay(&self) -> Duration {
+        let later = self.seen - self.first;
+        if later == 0 {
+            return Duration::ZERO;
+        }
+        self.total_delay / later as u32
+    }
+}
+
+// First-seen time of every pending transaction on every node, to find the node that
+// propagates transactions fastest
+pub struct Propagation {
+    nodes: Vec<String>,
+    stats: Vec<NodeStats>,
+    ttl: Duration,
+    max_entries: usize,
+    // per transaction, the nodes that delivered it in arrival order
+    sightings: HashMap<H256, Vec<Received>>,
+    order: VecDeque<H256>,
+}
+
+impl Propagation {
+    pub fn new(nodes: Vec<String>, ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            stats: vec![NodeStats::default(); nodes.len()],
+            nodes,
+            ttl,
+            max_entries,
+            sightings: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn observe(&mut self, tx_hash: H256, received: &Received) {
+        self.expire(received.at);
+
+        let sightings = match self.sightings.get_mut(&tx_hash) {
+            Some(sightings) => sightings,
+            None => {
+                if self.sightings.len() >= self.max_entries {
+                    if let Some(oldest) = self.order.pop_front() {
+                        self.sightings.remove(&oldest);
+                    }
+                }
+                self.order.push_back(tx_hash);
+                self.sightings.entry(tx_hash).or_default()
+            }
+        };
+
+        // only the first delivery of each node counts
+        if sightings.iter().any(|s| s.node == received.node) {
+            return;
+        }
+
+        // nodes of a recording are only known by index
+        while self.stats.len() <= received.node {
+            self.nodes.push(format!("node {}", self.stats.len()));
+            self.stats.push(NodeStats::default());
+        }
+
+        let stats = &mut self.stats[received.node];
+        stats.seen += 1;
+
+        match sightings.first() {
+            Some(first) => {
+                stats.total_delay += received.at.duration_since(first.at).unwrap_or_default();
+            }
+            None => stats.first += 1,
+        }
+
+        sightings.push(*received);
+    }
+
+    // Nodes that delivered the transaction so far, in arrival order
+    pub fn sightings(&self, tx_hash: &H256) -> Vec<Sighting> {
+        self.sightings
+            .get(tx_hash)
+            .map(|sightings| {
+                sightings
+                    .iter()
+                    .map(|received| Sighting {
+                        node: self.nodes[received.node].clone(),
+                        at: unix_micros(received.at),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // The node that was first most often
+    pub fn fastest(&self) -> Option<(&str, &NodeStats)> {
+        self.nodes
+            .iter()
+            .zip(self.stats.iter())
+            .filter(|(_, stats)| stats.seen > 0)
+            .max_by_key(|(_, stats)| stats.first)
+            .map(|(node, stats)| (node.as_str(), stats))
+    }
+
+    // Nothing to compare with a single node
+    pub fn report(&self) {
+        if self.nodes.len() < 2 {
+            return;
+        }
+
+        for (node, stats) in self.nodes.iter().zip(self.stats.iter()) {
+            info!(
+                "Node {}: seen={} first={} average_delay={:?}",
+                node,
+                stats.seen,
+                stats.first,
+                stats.average_delay()
+            );
+        }
+
+        if let Some((node, stats)) = self.fastest() {
+            info!(
+                "Fastest node: {} (first for {} of {} txs)",
+                node, stats.first, stats.seen
+            );
+        }
+    }
+
+    fn expire(&mut self, now: SystemTime) {
+        while let Some(oldest) = self.order.front() {
+            let first_seen = self.sightings.get(oldest).and_then(|s| s.first());
+            match first_seen {
+                Some(first) if now.duration_since(first.at).unwrap_or_default() < self.ttl => break,
+                _ => {
+                    let oldest = self.order.pop_front().unwrap();
+                    self.sightings.remove(&oldest);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn at(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000) + Duration::from_millis(millis)
+    }
+
+    fn received(node: usize, millis: u64) -> Received {
+        Received {
+            node,
+            at: at(millis),
+        }
+    }
+
+    fn hash(n: u64) -> H256 {
+        H256::from_low_u64_be(n)
+    }
+
+    fn propagation() -> Propagation {
+        Propagation::new(
+            vec![String::from("a"), String::from("b")],
+            Duration::from_secs(60),
+            100,
+        )
+    }
+
+    #[test]
+    fn only_the_first_delivery_of_each_node_counts() {
+        let mut propagation = propagation();
+        propagation.observe(hash(1), &received(1, 0));
+        propagation.observe(hash(1), &received(0, 5));
+        propagation.observe(hash(1), &received(1, 9));
+
+        assert_eq!(
+            propagation.sightings(&hash(1)),
+            vec![
+                Sighting {
+                    node: String::from("b"),
+                    at: unix_micros(at(0)),
+                },
+                Sighting {
+                    node: String::from("a"),
+                    at: unix_micros(at(5)),
+                },
+            ]
+        );
+        assert!(propagation.sightings(&hash(2)).is_empty());
+    }
+
+    #[test]
+    fn fastest_node_is_first_most_often() {
+        let mut propagation = propagation();
+        // b is first twice, 10ms and 30ms ahead of a
+        propagation.observe(hash(1), &received(1, 0));
+        propagation.observe(hash(1), &received(0, 10));
+        propagation.observe(hash(2), &received(0, 100));
+        propagation.observe(hash(2), &received(1, 120));
+        propagation.observe(hash(3), &received(1, 200));
+        propagation.observe(hash(3), &received(0, 230));
+
+        let (node, stats) = propagation.fastest().unwrap();
+        assert_eq!(node, "b");
+        assert_eq!((stats.seen, stats.first), (3, 2));
+        assert_eq!(stats.average_delay(), Duration::from_millis(20));
+
+        let a = &propagation.stats[0];
+        assert_eq!((a.seen, a.first), (3, 1));
+        assert_eq!(a.average_delay(), Duration::from_millis(20));
+    }
+
+    #[test]
+    fn no_node_is_fastest_before_any_delivery() {
+        assert!(propagation().fastest().is_none());
+    }
+
+    #[test]
+    fn nodes_of_a_recording_are_named_by_index() {
+        let mut propagation = Propagation::new(Vec::new(), Duration::from_secs(60), 100);
+        propagation.observe(hash(1), &received(2, 0));
+
+        assert_eq!(propagation.sightings(&hash(1))[0].node, "node 2");
+        assert_eq!(propagation.fastest().unwrap().0, "node 2");
+    }
+
+    #[test]
+    fn sightings_expire_after_the_ttl() {
+        let mut propagation = Propagation::new(Vec::new(), Duration::from_secs(1), 2);
+        propagation.observe(hash(1), &received(0, 0));
+        propagation.observe(hash(2), &received(0, 500));
+
+        // past the ttl of 1
+        propagation.observe(hash(3), &received(0, 1200));
+        assert!(propagation.sightings(&hash(1)).is_empty());
+        assert_eq!(propagation.sightings(&hash(2)).len(), 1);
+
+        // at the cap, 2 goes first
+        propagation.observe(hash(4), &received(0, 1300));
+        assert!(propagation.sightings(&hash(2)).is_empty());
+        assert_eq!(propagation.sightings(&hash(3)).len(), 1);
+        assert_eq!(propagation.sightings(&hash(4)).len(), 1);
+    }
+}
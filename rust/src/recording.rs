@@ -0,0 +1,554 @@
+use anyhow::{bail, Context, Result};
+use ethers::types::{Transaction, H256};
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::channel::EventSender;
+use crate::error::WatcherError;
+use crate::metrics::Metrics;
+use crate::trace::{Event, NewBlock, Received};
+use crate::utils::unix_micros;
+
+const FILE_PREFIX: &str = "events-";
+const FILE_EXTENSION: &str = ".jsonl.gz";
+// Records read ahead of the playback schedule
+const PLAY_BUFFER: usize = 1024;
+
+// One line of a recording, `at` is the arrival time in microseconds since the unix epoch
+// and `node` the index of the node it arrived from
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Record {
+    NewBlock {
+        at: u64,
+        node: usize,
+        block: Box<NewBlock>,
+    },
+    Transaction {
+        at: u64,
+        node: usize,
+        tx: Box<Transaction>,
+    },
+}
+
+impl Record {
+    // Reorgs and reconnects are not recorded, they follow from the headers
+    pub fn of(event: &Event) -> Option<Self> {
+        match event {
+            Event::NewBlock { block, received } => Some(Record::NewBlock {
+                at: unix_micros(received.at),
+                node: received.node,
+                block: block.clone(),
+            }),
+            Event::Transaction { tx, received } => Some(Record::Transaction {
+                at: unix_micros(received.at),
+                node: received.node,
+                tx: tx.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn at(&self) -> u64 {
+        match self {
+            Record::NewBlock { at, .. } | Record::Transaction { at, .. } => *at,
+        }
+    }
+
+    pub fn into_event(self) -> Event {
+        match self {
+            Record::NewBlock { at, node, block } => Event::NewBlock {
+                block,
+                received: received(node, at),
+            },
+            Record::Transaction { at, node, tx } => Event::Transaction {
+                tx,
+                received: received(node, at),
+            },
+        }
+    }
+}
+
+// Writes events as gzipped JSON lines to `dir`, starting a new file every `max_file_bytes`
+// (compressed)
+pub struct Recorder {
+    dir: PathBuf,
+    max_file_bytes: u64,
+    // 0 keeps every file
+    max_files: usize,
+    file: Option<GzEncoder<File>>,
+    // bytes on disk as of the last flush
+    written: u64,
+}
+
+impl Recorder {
+    pub fn new(dir: impl Into<PathBuf>, max_file_bytes: u64, max_files: usize) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+
+        Ok(Self {
+            dir,
+            max_file_bytes,
+            max_files,
+            file: None,
+            written: 0,
+        })
+    }
+
+    // Writes on a blocking thread from now on, so the handler never waits for the disk
+    pub fn spawn(mut self, capacity: usize, metrics: Arc<Metrics>) -> RecorderHandle {
+        let (sender, mut receiver) = mpsc::channel::<Record>(capacity);
+
+        let writer = {
+            let metrics = metrics.clone();
+
+            tokio::task::spawn_blocking(move || {
+                while let Some(record) = receiver.blocking_recv() {
+                    if let Err(e) = self.write(&record) {
+                        let e = WatcherError::Recording(e.to_string());
+                        metrics.record_error(&e);
+                        warn!("{}", e);
+                    }
+                }
+
+                if let Err(e) = self.finish() {
+                    let e = WatcherError::Recording(e.to_string());
+                    metrics.record_error(&e);
+                    warn!("{}", e);
+                }
+            })
+        };
+
+        RecorderHandle {
+            sender,
+            metrics,
+            writer,
+        }
+    }
+
+    pub fn write(&mut self, record: &Record) -> Result<()> {
+        if self.file.is_none() || self.written >= self.max_file_bytes {
+            self.rotate()?;
+        }
+
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(&line)?;
+            // keeps the loss small when the watcher is killed, a file cut off after the last
+            // flush is still readable up to there
+            if let Record::NewBlock { .. } = record {
+                file.flush()?;
+                self.written = file.get_ref().metadata()?.len();
+            }
+        }
+
+        Ok(())
+    }
+
+    // Completes the current file, the next write starts a new one
+    pub fn finish(&mut self) -> Result<()> {
+        if let Some(file) = self.file.take() {
+            file.finish()?.sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.finish()?;
+
+        // named by creation time, so they sort in recording order
+        let mut at = unix_micros(SystemTime::now());
+        let path = loop {
+            let path = self
+                .dir
+                .join(format!("{}{:016}{}", FILE_PREFIX, at, FILE_EXTENSION));
+            if !path.exists() {
+                break path;
+            }
+            at += 1;
+        };
+        let file =
+            File::create(&path).with_context(|| format!("failed to create {}", path.display()))?;
+        self.file = Some(GzEncoder::new(file, Compression::default()));
+        self.written = 0;
+
+        if self.max_files > 0 {
+            let files = recording_files(&self.dir)?;
+            for old in files
+                .iter()
+                .take(files.len().saturating_sub(self.max_files))
+            {
+                std::fs::remove_file(old)
+                    .with_context(|| format!("failed to remove {}", old.display()))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Queues events for a Recorder running on its own thread
+pub struct RecorderHandle {
+    sender: mpsc::Sender<Record>,
+    metrics: Arc<Metrics>,
+    writer: JoinHandle<()>,
+}
+
+impl RecorderHandle {
+    // Drops the event when the writer is `capacity` records behind
+    pub fn record(&self, event: &Event) {
+        let Some(record) = Record::of(event) else {
+            return;
+        };
+
+        if let Err(e) = self.sender.try_send(record) {
+            let e = WatcherError::Recording(match e {
+                TrySendError::Full(_) => String::from("writer is behind, event dropped"),
+                TrySendError::Closed(_) => String::from("writer stopped"),
+            });
+            self.metrics.record_error(&e);
+            warn!("{}", e);
+        }
+    }
+
+    // Waits until everything queued is written
+    pub async fn close(self) {
+        drop(self.sender);
+        let _ = self.writer.await;
+    }
+}
+
+// `path` itself if it is a file, otherwise the recordings in the directory, oldest first
+pub fn recording_files(path: &Path) -> Result<Vec<PathBuf>> {
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut files = Vec::new();
+    for entry in
+        std::fs::read_dir(path).with_context(|| format!("failed to read {}", path.display()))?
+    {
+        let path = entry?.path();
+        let is_recording = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(FILE_PREFIX) && name.ends_with(FILE_EXTENSION));
+
+        if is_recording {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+fn received(node: usize, at: u64) -> Received {
+    Received {
+        node,
+        at: UNIX_EPOCH + Duration::from_micros(at),
+    }
+}
+
+// Passes the records of `path` to `f` until it returns false. Malformed lines are skipped and
+// a truncated file (the watcher was killed) is read up to the cut, both with a warning.
+fn read_records(path: &Path, mut f: impl FnMut(Record) -> bool) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+
+    for (i, line) in BufReader::new(MultiGzDecoder::new(file))
+        .lines()
+        .enumerate()
+    {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("{}:{}: stopped reading: {}", path.display(), i + 1, e);
+                break;
+            }
+        };
+        if line.is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str(&line) {
+            Ok(record) => {
+                if !f(record) {
+                    break;
+                }
+            }
+            Err(e) => warn!(
+                "{}:{}: skipped invalid record: {}",
+                path.display(),
+                i + 1,
+                e
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+// Hashes of the pending transactions in the recordings
+pub fn recorded_tx_hashes(files: &[PathBuf]) -> Result<HashSet<H256>> {
+    let mut hashes = HashSet::new();
+
+    for path in files {
+        read_records(path, |record| {
+            if let Record::Transaction { tx, .. } = record {
+                hashes.insert(tx.hash);
+            }
+            true
+        })?;
+    }
+
+    Ok(hashes)
+}
+
+// Sends the recorded events in order, `speed` times faster than they arrived.
+// Returns the number of events sent, stops early when the handler is gone.
+pub async fn play(files: Vec<PathBuf>, speed: f64, event_sender: EventSender) -> Result<usize> {
+    if files.is_empty() {
+        bail!("no recordings to play");
+    }
+
+    // decompressing is blocking, the files are read on their own thread
+    let (sender, mut receiver) = mpsc::channel(PLAY_BUFFER);
+    let reader = tokio::task::spawn_blocking(move || -> Result<()> {
+        for path in &files {
+            read_records(path, |record| sender.blocking_send(record).is_ok())?;
+        }
+        Ok(())
+    });
+
+    let start = Instant::now();
+    let mut first_at = None;
+    let mut played = 0;
+
+    while let Some(record) = receiver.recv().await {
+        // the schedule is relative to the first event, so sleeps don't add up
+        let first_at = *first_at.get_or_insert(record.at());
+        let offset = Duration::from_micros(record.at().saturating_sub(first_at));
+        tokio::time::sleep_until(start + offset.div_f64(speed)).await;
+
+        if !event_sender.send(record.into_event()).await {
+            return Ok(played);
+        }
+        played += 1;
+    }
+    reader.await??;
+
+    Ok(played)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::event_channel;
+    use crate::config::LagPolicy;
+    use ethers::types::U64;
+
+    // A fresh directory under the system temp dir, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "recording-{}-{}-{}",
+                name,
+                std::process::id(),
+                unix_micros(SystemTime::now())
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn block_record(number: u64, at: u64) -> Record {
+        Record::NewBlock {
+            at,
+            node: 0,
+            block: Box::new(NewBlock {
+                number: U64::from(number),
+                hash: H256::from_low_u64_be(number),
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn tx_record(hash: u64, at: u64, node: usize) -> Record {
+        Record::Transaction {
+            at,
+            node,
+            tx: Box::new(Transaction {
+                hash: H256::from_low_u64_be(hash),
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn record_all(dir: &Path, max_file_bytes: u64, max_files: usize, records: &[Record]) {
+        let mut recorder = Recorder::new(dir, max_file_bytes, max_files).unwrap();
+        for record in records {
+            recorder.write(record).unwrap();
+        }
+        recorder.finish().unwrap();
+    }
+
+    async fn play_all(files: Vec<PathBuf>) -> Vec<Event> {
+        let (event_sender, mut event_receiver) =
+            event_channel(LagPolicy::Backpressure, 64, Arc::new(Metrics::default()));
+        let played = tokio::spawn(play(files, 1e6, event_sender));
+
+        let mut events = Vec::new();
+        while let Some(event) = event_receiver.recv().await {
+            events.push(event);
+        }
+        assert_eq!(played.await.unwrap().unwrap(), events.len());
+        events
+    }
+
+    fn block_numbers(path: &Path) -> Vec<u64> {
+        let mut numbers = Vec::new();
+        read_records(path, |record| {
+            if let Record::NewBlock { block, .. } = record {
+                numbers.push(block.number.as_u64());
+            }
+            true
+        })
+        .unwrap();
+        numbers
+    }
+
+    #[tokio::test]
+    async fn recorded_events_play_back_in_order() {
+        let dir = TempDir::new("round-trip");
+        record_all(
+            &dir.0,
+            u64::MAX,
+            0,
+            &[
+                block_record(10, 1_000),
+                tx_record(1, 1_500, 1),
+                tx_record(2, 2_000, 0),
+                block_record(11, 3_000),
+            ],
+        );
+
+        let files = recording_files(&dir.0).unwrap();
+        assert_eq!(files.len(), 1);
+
+        let events = play_all(files.clone()).await;
+        assert_eq!(events.len(), 4);
+        match &events[1] {
+            Event::Transaction { tx, received } => {
+                assert_eq!(tx.hash, H256::from_low_u64_be(1));
+                assert_eq!(received.node, 1);
+                assert_eq!(unix_micros(received.at), 1_500);
+            }
+            event => panic!("expected a transaction, got {:?}", event),
+        }
+        match &events[3] {
+            Event::NewBlock { block, received } => {
+                assert_eq!(block.number, U64::from(11));
+                assert_eq!(unix_micros(received.at), 3_000);
+            }
+            event => panic!("expected a header, got {:?}", event),
+        }
+
+        assert_eq!(
+            recorded_tx_hashes(&files).unwrap(),
+            HashSet::from([H256::from_low_u64_be(1), H256::from_low_u64_be(2)])
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_and_truncated_lines_are_skipped() {
+        let dir = TempDir::new("malformed");
+        let path = dir
+            .0
+            .join(format!("{}{:016}{}", FILE_PREFIX, 0, FILE_EXTENSION));
+
+        let mut file = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        let write = |file: &mut GzEncoder<File>, line: String| {
+            file.write_all(format!("{}\n", line).as_bytes()).unwrap()
+        };
+        write(
+            &mut file,
+            serde_json::to_string(&block_record(10, 1_000)).unwrap(),
+        );
+        write(&mut file, String::from("{\"kind\":\"new_block\",\"at\":"));
+        write(
+            &mut file,
+            serde_json::to_string(&tx_record(1, 1_500, 0)).unwrap(),
+        );
+        file.flush().unwrap();
+        let flushed = file.get_ref().metadata().unwrap().len();
+        write(
+            &mut file,
+            serde_json::to_string(&tx_record(2, 2_000, 0)).unwrap(),
+        );
+        file.finish().unwrap();
+
+        // the watcher was killed before the last record was flushed
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(flushed)
+            .unwrap();
+
+        let events = play_all(vec![path.clone()]).await;
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[0], Event::NewBlock { .. }));
+        assert!(matches!(events[1], Event::Transaction { .. }));
+        assert_eq!(
+            recorded_tx_hashes(&[path]).unwrap(),
+            HashSet::from([H256::from_low_u64_be(1)])
+        );
+    }
+
+    #[test]
+    fn files_are_rotated_by_size() {
+        let dir = TempDir::new("rotation");
+        let records: Vec<_> = (0..4).map(|i| block_record(10 + i, i * 1_000)).collect();
+        // every flushed header fills a file
+        record_all(&dir.0, 1, 0, &records);
+
+        let files = recording_files(&dir.0).unwrap();
+        let numbers: Vec<_> = files.iter().map(|file| block_numbers(file)).collect();
+        assert_eq!(numbers, vec![vec![10], vec![11], vec![12], vec![13]]);
+    }
+
+    #[test]
+    fn oldest_files_are_pruned_beyond_max_files() {
+        let dir = TempDir::new("pruning");
+        let records: Vec<_> = (0..5).map(|i| block_record(10 + i, i * 1_000)).collect();
+        record_all(&dir.0, 1, 2, &records);
+
+        let files = recording_files(&dir.0).unwrap();
+        let numbers: Vec<_> = files.iter().map(|file| block_numbers(file)).collect();
+        assert_eq!(numbers, vec![vec![13], vec![14]]);
+    }
+}
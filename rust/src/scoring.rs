@@ -0,0 +1,244 @@
+use ethers::types::{Transaction, H256, U256, U64};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, ops::Bound};
+
+use crate::trace::NewBlock;
+use crate::utils::{
+    calculate_blob_base_fee, calculate_next_block_base_fee, calculate_next_block_excess_blob_gas,
+    TxFees,
+};
+
+// Slot time, to tell which fork the next block is in
+const SECONDS_PER_SLOT: u64 = 12;
+
+// How likely a pending transaction is to land in the next block
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InclusionScore {
+    // block the score was computed for
+    pub block_number: U64,
+    pub effective_gas_price: U256,
+    pub effective_tip: U256,
+    // pending transactions seen so far that pay a higher tip, 0 is the front of the block
+    pub position: usize,
+    // gas limit of the transactions ahead of it
+    pub gas_ahead: U256,
+    pub fits_next_block: bool,
+}
+
+// Tips of the pending transactions seen since the last block, builders are
+// assumed to order the next block by effective tip
+#[derive(Debug, Clone, Default)]
+pub struct TipBook {
+    block_number: U64,
+    base_fee: U256,
+    // None before Cancun
+    blob_base_fee: Option<U256>,
+    gas_limit: U256,
+    // effective tip -> hash and gas limit of the transactions paying it
+    tips: BTreeMap<U256, Vec<(H256, U256)>>,
+}
+
+impl TipBook {
+    // Starts an empty book for the block after `block`
+    pub fn new(block: &NewBlock) -> Self {
+        let timestamp = block.timestamp + SECONDS_PER_SLOT;
+        let blob_base_fee = match (block.excess_blob_gas, block.blob_gas_used) {
+            (Some(excess_blob_gas), Some(blob_gas_used)) => {
+                let excess_blob_gas = calculate_next_block_excess_blob_gas(
+                    excess_blob_gas,
+                    blob_gas_used,
+                    block.base_fee_per_gas,
+                    timestamp,
+                );
+                Some(calculate_blob_base_fee(excess_blob_gas, timestamp))
+            }
+            _ => None,
+        };
+
+        Self {
+            block_number: block.number + 1,
+            base_fee: calculate_next_block_base_fee(
+                block.gas_used,
+                block.gas_limit,
+                block.base_fee_per_gas,
+            ),
+            blob_base_fee,
+            gas_limit: block.gas_limit,
+            tips: BTreeMap::new(),
+        }
+    }
+
+    pub fn base_fee(&self) -> U256 {
+        self.base_fee
+    }
+
+    pub fn blob_base_fee(&self) -> Option<U256> {
+        self.blob_base_fee
+    }
+
+    // Transactions that can't pay the next base fee are left out
+    pub fn insert(&mut self, tx: &Transaction, fees: &TxFees) {
+        if let Some(tip) = fees.priority_fee(self.base_fee) {
+            self.tips.entry(tip).or_default().push((tx.hash, tx.gas));
+        }
+    }
+
+    // Drops a transaction that was replaced, it can't be mined anymore
+    pub fn remove(&mut self, tx: &Transaction) {
+        let Some(tip) = TxFees::of(tx).priority_fee(self.base_fee) else {
+            return;
+        };
+
+        if let Some(txs) = self.tips.get_mut(&tip) {
+            txs.retain(|(hash, _)| *hash != tx.hash);
+            if txs.is_empty() {
+                self.tips.remove(&tip);
+            }
+        }
+    }
+
+    pub fn score(&self, fees: &TxFees, gas: U256) -> Option<InclusionScore> {
+        let effective_tip = fees.priority_fee(self.base_fee)?;
+
+        let (position, gas_ahead) = self
+            .tips
+            .range((Bound::Excluded(effective_tip), Bound::Unbounded))
+            .flat_map(|(_, txs)| txs.iter())
+            .fold((0, U256::zero()), |(position, gas_ahead), (_, gas)| {
+                (position + 1, gas_ahead.saturating_add(*gas))
+            });
+
+        Some(InclusionScore {
+            block_number: self.block_number,
+            effective_gas_price: self.base_fee + effective_tip,
+            effective_tip,
+            position,
+            gas_ahead,
+            fits_next_block: gas_ahead.saturating_add(gas) <= self.gas_limit,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GWEI: u64 = 1_000_000_000;
+
+    fn book(gas_limit: u64) -> TipBook {
+        TipBook::new(&NewBlock {
+            number: U64::from(100),
+            gas_used: U256::from(gas_limit / 2),
+            gas_limit: U256::from(gas_limit),
+            base_fee_per_gas: U256::from(10 * GWEI),
+            ..Default::default()
+        })
+    }
+
+    // EIP-1559 transaction paying `tip` gwei on top of the 10 gwei base fee
+    fn tx(hash: u64, tip: u64, gas: u64) -> (Transaction, TxFees) {
+        let tx = Transaction {
+            hash: H256::from_low_u64_be(hash),
+            gas: U256::from(gas),
+            max_fee_per_gas: Some(U256::from(100 * GWEI)),
+            max_priority_fee_per_gas: Some(U256::from(tip * GWEI)),
+            ..Default::default()
+        };
+        let fees = TxFees::of(&tx);
+        (tx, fees)
+    }
+
+    fn book_with(gas_limit: u64, txs: &[(Transaction, TxFees)]) -> TipBook {
+        let mut book = book(gas_limit);
+        for (tx, fees) in txs {
+            book.insert(tx, fees);
+        }
+        book
+    }
+
+    #[test]
+    fn higher_tips_are_ahead() {
+        let book = book_with(
+            1_000_000,
+            &[tx(1, 3, 100_000), tx(2, 1, 200_000), tx(3, 5, 300_000)],
+        );
+
+        let (_, fees) = tx(4, 2, 50_000);
+        let score = book.score(&fees, U256::from(50_000)).unwrap();
+        assert_eq!(score.block_number, U64::from(101));
+        assert_eq!(score.effective_tip, U256::from(2 * GWEI));
+        assert_eq!(score.effective_gas_price, U256::from(12 * GWEI));
+        assert_eq!(score.position, 2);
+        assert_eq!(score.gas_ahead, U256::from(400_000));
+        assert!(score.fits_next_block);
+
+        // the highest tip is at the front
+        let (_, fees) = tx(5, 6, 50_000);
+        let score = book.score(&fees, U256::from(50_000)).unwrap();
+        assert_eq!(score.position, 0);
+        assert_eq!(score.gas_ahead, U256::zero());
+    }
+
+    #[test]
+    fn same_tip_is_not_ahead() {
+        let book = book_with(1_000_000, &[tx(1, 2, 100_000), tx(2, 2, 100_000)]);
+
+        let (_, fees) = tx(3, 2, 100_000);
+        let score = book.score(&fees, U256::from(100_000)).unwrap();
+        assert_eq!(score.position, 0);
+        assert_eq!(score.gas_ahead, U256::zero());
+    }
+
+    #[test]
+    fn transactions_past_the_gas_limit_do_not_fit() {
+        let book = book_with(1_000_000, &[tx(1, 3, 600_000), tx(2, 4, 300_000)]);
+
+        let (_, fees) = tx(3, 2, 100_000);
+        assert!(
+            book.score(&fees, U256::from(100_000))
+                .unwrap()
+                .fits_next_block
+        );
+        assert!(
+            !book
+                .score(&fees, U256::from(100_001))
+                .unwrap()
+                .fits_next_block
+        );
+    }
+
+    #[test]
+    fn removed_transactions_are_not_ahead() {
+        let first = tx(1, 3, 100_000);
+        let second = tx(2, 3, 200_000);
+        let mut book = book_with(1_000_000, &[first.clone(), second.clone()]);
+
+        book.remove(&first.0);
+        let (_, fees) = tx(3, 2, 100_000);
+        let score = book.score(&fees, U256::from(100_000)).unwrap();
+        assert_eq!(score.position, 1);
+        assert_eq!(score.gas_ahead, U256::from(200_000));
+
+        // removing twice, or what was never inserted, changes nothing
+        book.remove(&first.0);
+        book.remove(&tx(4, 5, 100_000).0);
+        assert_eq!(book.score(&fees, U256::from(100_000)).unwrap().position, 1);
+
+        book.remove(&second.0);
+        assert_eq!(book.score(&fees, U256::from(100_000)).unwrap().position, 0);
+    }
+
+    #[test]
+    fn transactions_below_the_base_fee_are_not_scored() {
+        let mut book = book(1_000_000);
+        let (mut underpriced, _) = tx(1, 1, 100_000);
+        underpriced.max_fee_per_gas = Some(U256::from(9 * GWEI));
+        let fees = TxFees::of(&underpriced);
+
+        book.insert(&underpriced, &fees);
+        assert!(book.score(&fees, U256::from(100_000)).is_none());
+
+        let (_, fees) = tx(2, 0, 100_000);
+        assert_eq!(book.score(&fees, U256::from(100_000)).unwrap().position, 0);
+    }
+}
@@ -0,0 +1,750 @@
+use async_trait::async_trait;
+use ethers::{
+    providers::Middleware,
+    types::{
+        AccountDiff, Block, BlockId, BlockNumber, Bytes, Diff, StateDiff, Transaction, H160, H256,
+        U256, U64,
+    },
+};
+use log::{debug, warn};
+use revm::{
+    db::{CacheDB, Database, DatabaseCommit, DatabaseRef, DbAccount, EthersDB},
+    primitives::{
+        Address as rAddress, BlobExcessGasAndPrice, BlockEnv, Bytecode, EVMError, HashMap,
+        ResultAndState, SpecId, State, TransactTo, TxEnv, B256, U256 as rU256,
+    },
+    Evm,
+};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use crate::backend::{diff, TraceBackend};
+use crate::error::WatcherError;
+use crate::metrics::Metrics;
+use crate::utils::TxFees;
+
+type ForkDB<M> = CacheDB<EthersDB<M>>;
+
+// State of a block, loaded lazily from the provider and reused for every
+// pending transaction simulated on top of it
+struct Fork<M: Middleware> {
+    block_number: U64,
+    block_env: BlockEnv,
+    // traces only read it, each one executes on a layer of its own
+    db: RwLock<ForkDB<M>>,
+}
+
+// Simulates transactions in-process with revm instead of tracing them on the node.
+// Only the accounts and storage slots a transaction reads are fetched over RPC,
+// and they are cached until the next block.
+pub struct RevmBackend<M: Middleware> {
+    provider: Arc<M>,
+    fork: Mutex<Option<Arc<Fork<M>>>>,
+    metrics: Arc<Metrics>,
+}
+
+impl<M: Middleware> RevmBackend<M> {
+    pub fn new(provider: Arc<M>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            provider,
+            fork: Mutex::new(None),
+            metrics,
+        }
+    }
+
+    // Fork of `block`, shared by every trace on that block. A trace that started before
+    // the next block arrived gets a fork of its own block, which never replaces a newer one.
+    async fn fork(&self, block: BlockNumber) -> Result<Arc<Fork<M>>, WatcherError> {
+        // there is no pending state to fork, pending transactions run on top of the latest block
+        let block_number = match block.as_number() {
+            Some(number) => number,
+            None => self
+                .provider
+                .get_block_number()
+                .await
+                .map_err(WatcherError::rpc)?,
+        };
+
+        if let Some(fork) = self.fork.lock().unwrap().as_ref() {
+            if fork.block_number == block_number {
+                return Ok(fork.clone());
+            }
+        }
+
+        let block = self
+            .provider
+            .get_block(block_number)
+            .await
+            .map_err(WatcherError::rpc)?
+            .ok_or(WatcherError::BlockNotFound(block_number.as_u64()))?;
+
+        // pending transactions land in the next block
+        let new_fork = Arc::new(Fork {
+            block_number,
+            block_env: next_block_env(&block)?,
+            db: RwLock::new(fork_db(self.provider.clone(), block_number)?),
+        });
+
+        // another trace may have installed a fork while this one was fetching the block
+        let mut fork = self.fork.lock().unwrap();
+        match fork.as_ref() {
+            Some(current) if current.block_number == block_number => Ok(current.clone()),
+            Some(current) if current.block_number > block_number => Ok(new_fork),
+            _ => {
+                *fork = Some(new_fork.clone());
+                Ok(new_fork)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<M: Middleware + 'static> TraceBackend for RevmBackend<M>
+where
+    M::Error: 'static,
+{
+    async fn trace_call(
+        &self,
+        tx: &Transaction,
+        block: BlockNumber,
+    ) -> Result<StateDiff, WatcherError> {
+        let fork = self.fork(block).await?;
+        let tx = tx.clone();
+
+        // revm is synchronous, and EthersDB blocks on the provider for every cache miss
+        tokio::task::spawn_blocking(move || {
+            let (state_diff, accounts, contracts) = {
+                let db = fork.db.read().unwrap();
+                let mut layer = CacheDB::new(&*db);
+                let state = transact(&mut layer, fork.block_env.clone(), &tx)?;
+
+                (
+                    to_state_diff(&layer, &state),
+                    layer.accounts,
+                    layer.contracts,
+                )
+            };
+
+            // nothing was committed to the layer, what it loaded is the state of the block.
+            // Skipped while other traces are reading the fork, they load it again if needed.
+            if let Ok(mut db) = fork.db.try_write() {
+                warm(&mut db, accounts, contracts);
+            }
+
+            Ok(state_diff)
+        })
+        .await
+        .map_err(|e| WatcherError::Simulation(e.to_string()))?
+    }
+
+    async fn trace_call_many(
+        &self,
+        txs: &[Transaction],
+        block: BlockNumber,
+    ) -> Result<StateDiff, WatcherError> {
+        let fork = self.fork(block).await?;
+        let txs = txs.to_vec();
+        let metrics = self.metrics.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let (tx, preceding) = txs
+                .split_last()
+                .ok_or(WatcherError::Simulation(String::from("no transactions")))?;
+
+            // the preceding transactions are committed to a layer on top of the fork,
+            // so the fork itself keeps the state of its block
+            let fork_db = fork.db.read().unwrap();
+            let mut db = CacheDB::new(&*fork_db);
+            commit_preceding(&mut db, &fork.block_env, preceding, tx, &metrics);
+
+            let state = transact(&mut db, fork.block_env.clone(), tx)?;
+
+            Ok(to_state_diff(&db, &state))
+        })
+        .await
+        .map_err(|e| WatcherError::Simulation(e.to_string()))?
+    }
+
+    async fn trace_replay(&self, tx_hash: H256) -> Result<StateDiff, WatcherError> {
+        let tx = self
+            .provider
+            .get_transaction(tx_hash)
+            .await
+            .map_err(WatcherError::rpc)?
+            .ok_or(WatcherError::TransactionNotFound(tx_hash))?;
+        let block_number = tx
+            .block_number
+            .ok_or(WatcherError::TransactionNotMined(tx_hash))?;
+        let block = self
+            .provider
+            .get_block_with_txs(block_number)
+            .await
+            .map_err(WatcherError::rpc)?
+            .ok_or(WatcherError::BlockNotFound(block_number.as_u64()))?;
+
+        let block_env = block_env(&block)?;
+        let mut db = fork_db(self.provider.clone(), block_number - 1)?;
+        let metrics = self.metrics.clone();
+
+        tokio::task::spawn_blocking(move || {
+            // replay everything before the transaction in its block
+            let position = block
+                .transactions
+                .iter()
+                .position(|preceding| preceding.hash == tx_hash)
+                .unwrap_or(block.transactions.len());
+            commit_preceding(
+                &mut db,
+                &block_env,
+                &block.transactions[..position],
+                &tx,
+                &metrics,
+            );
+
+            let state = transact(&mut db, block_env, &tx)?;
+
+            Ok(to_state_diff(&db, &state))
+        })
+        .await
+        .map_err(|e| WatcherError::Simulation(e.to_string()))?
+    }
+
+    async fn trace_replay_block(&self, block: u64) -> Result<Vec<(H256, StateDiff)>, WatcherError> {
+        let block = self
+            .provider
+            .get_block_with_txs(block)
+            .await
+            .map_err(WatcherError::rpc)?
+            .ok_or(WatcherError::BlockNotFound(block))?;
+
+        let block_env = block_env(&block)?;
+        let parent = block.number.unwrap_or_default().saturating_sub(U64::one());
+        let mut db = fork_db(self.provider.clone(), parent)?;
+        let metrics = self.metrics.clone();
+
+        tokio::task::spawn_blocking(move || {
+            Ok(replay_txs(
+                &mut db,
+                &block_env,
+                &block.transactions,
+                &metrics,
+            ))
+        })
+        .await
+        .map_err(|e| WatcherError::Simulation(e.to_string()))?
+    }
+}
+
+// One pass over the transactions of a block, every transaction runs on the state the previous
+// ones left. A transaction that can't be executed is counted and left out, the rest still runs.
+fn replay_txs<ExtDB: DatabaseRef>(
+    db: &mut CacheDB<ExtDB>,
+    block_env: &BlockEnv,
+    txs: &[Transaction],
+    metrics: &Metrics,
+) -> Vec<(H256, StateDiff)>
+where
+    ExtDB::Error: std::fmt::Debug,
+{
+    let mut state_diffs = Vec::with_capacity(txs.len());
+
+    for tx in txs {
+        match transact(db, block_env.clone(), tx) {
+            Ok(state) => {
+                state_diffs.push((tx.hash, to_state_diff(db, &state)));
+                db.commit(state);
+            }
+            Err(e) => {
+                metrics.record_error(&e);
+                warn!("Tx #{:?}: {}", tx.hash, e);
+            }
+        }
+    }
+
+    state_diffs
+}
+
+fn fork_db<M: Middleware>(provider: Arc<M>, block_number: U64) -> Result<ForkDB<M>, WatcherError> {
+    let ethers_db = EthersDB::new(provider, Some(BlockId::from(block_number))).ok_or(
+        WatcherError::Simulation(format!("failed to fork block {}", block_number)),
+    )?;
+
+    Ok(CacheDB::new(ethers_db))
+}
+
+// Adds the accounts, storage and code a layer loaded to the fork, keeping what the fork has
+fn warm<M: Middleware>(
+    db: &mut ForkDB<M>,
+    accounts: HashMap<rAddress, DbAccount>,
+    contracts: HashMap<B256, Bytecode>,
+) {
+    for (address, account) in accounts {
+        match db.accounts.get_mut(&address) {
+            Some(cached) => {
+                for (slot, value) in account.storage {
+                    cached.storage.entry(slot).or_insert(value);
+                }
+            }
+            None => {
+                db.accounts.insert(address, account);
+            }
+        }
+    }
+
+    for (hash, code) in contracts {
+        db.contracts.entry(hash).or_insert(code);
+    }
+}
+
+// Commits the transactions preceding `tx`, one that can't be executed is left out of the sequence
+fn commit_preceding<DB: Database + DatabaseCommit>(
+    db: &mut DB,
+    block_env: &BlockEnv,
+    preceding: &[Transaction],
+    tx: &Transaction,
+    metrics: &Metrics,
+) where
+    DB::Error: std::fmt::Debug,
+{
+    for preceding in preceding {
+        if let Err(e) = evm(db, block_env.clone(), preceding).transact_commit() {
+            let e = WatcherError::PrecedingTxFailed(preceding.hash, format!("{:?}", e));
+            metrics.record_error(&e);
+            debug!("Tx #{:?}: {}", tx.hash, e);
+        }
+    }
+}
+
+// Hardfork of a block, only mainnet is known, other chains are assumed to be up to date
+fn spec_id(chain_id: u64, block_env: &BlockEnv) -> SpecId {
+    if chain_id != 1 {
+        return SpecId::CANCUN;
+    }
+
+    let number = block_env.number.saturating_to::<u64>();
+    let timestamp = block_env.timestamp.saturating_to::<u64>();
+
+    match (number, timestamp) {
+        (_, 1710338135..) => SpecId::CANCUN,
+        (_, 1681338455..) => SpecId::SHANGHAI,
+        (15537394.., _) => SpecId::MERGE,
+        (15050000.., _) => SpecId::GRAY_GLACIER,
+        (13773000.., _) => SpecId::ARROW_GLACIER,
+        (12965000.., _) => SpecId::LONDON,
+        (12244000.., _) => SpecId::BERLIN,
+        (9200000.., _) => SpecId::MUIR_GLACIER,
+        (9069000.., _) => SpecId::ISTANBUL,
+        (7280000.., _) => SpecId::PETERSBURG,
+        (4370000.., _) => SpecId::BYZANTIUM,
+        (2675000.., _) => SpecId::SPURIOUS_DRAGON,
+        (2463000.., _) => SpecId::TANGERINE,
+        (1920000.., _) => SpecId::DAO_FORK,
+        (1150000.., _) => SpecId::HOMESTEAD,
+        (200000.., _) => SpecId::FRONTIER_THAWING,
+        _ => SpecId::FRONTIER,
+    }
+}
+
+fn evm<'a, DB: Database>(
+    db: &'a mut DB,
+    block_env: BlockEnv,
+    tx: &Transaction,
+) -> Evm<'a, (), &'a mut DB> {
+    let chain_id = tx.chain_id.map(|id| id.as_u64()).unwrap_or(1);
+
+    Evm::builder()
+        .with_db(db)
+        .with_spec_id(spec_id(chain_id, &block_env))
+        .modify_cfg_env(|cfg| cfg.chain_id = chain_id)
+        .modify_block_env(|env| *env = block_env)
+        .modify_tx_env(|env| *env = tx_env(tx))
+        .build()
+}
+
+// Executes without committing, so the fork keeps the state of its block
+fn transact<DB: Database>(
+    db: &mut DB,
+    block_env: BlockEnv,
+    tx: &Transaction,
+) -> Result<State, WatcherError>
+where
+    DB::Error: std::fmt::Debug,
+{
+    let ResultAndState { state, .. } = evm(db, block_env, tx).transact().map_err(evm_error)?;
+    Ok(state)
+}
+
+fn evm_error<E: std::fmt::Debug>(e: EVMError<E>) -> WatcherError {
+    WatcherError::Simulation(format!("{:?}", e))
+}
+
+fn tx_env(tx: &Transaction) -> TxEnv {
+    let fees = TxFees::of(tx);
+
+    TxEnv {
+        caller: r_address(tx.from),
+        gas_limit: tx.gas.as_u64(),
+        gas_price: r_u256(fees.max_fee_per_gas),
+        gas_priority_fee: fees.max_priority_fee_per_gas.map(r_u256),
+        transact_to: match tx.to {
+            Some(to) => TransactTo::Call(r_address(to)),
+            None => TransactTo::create(),
+        },
+        value: r_u256(tx.value),
+        data: tx.input.0.clone().into(),
+        // pending transactions may sit behind other pending transactions of the same sender
+        nonce: None,
+        chain_id: tx.chain_id.map(|id| id.as_u64()),
+        access_list: tx
+            .access_list
+            .as_ref()
+            .map(|list| {
+                list.0
+                    .iter()
+                    .map(|item| {
+                        let keys = item
+                            .storage_keys
+                            .iter()
+                            .map(|key| rU256::from_be_bytes(key.to_fixed_bytes()))
+                            .collect();
+                        (r_address(item.address), keys)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+        blob_hashes: fees
+            .blob_versioned_hashes
+            .iter()
+            .map(|hash| B256::from(hash.0))
+            .collect(),
+        max_fee_per_blob_gas: fees.max_fee_per_blob_gas.map(r_u256),
+    }
+}
+
+fn block_env<TX>(block: &Block<TX>) -> Result<BlockEnv, WatcherError> {
+    let number = block.number.ok_or(WatcherError::Simulation(String::from(
+        "block has no number",
+    )))?;
+
+    // revm 7 stops at Cancun, Prague headers are the first to commit to requests (EIP-7685).
+    // Their blocks would run without EIP-7702 authorizations, the EIP-7623 calldata floor
+    // and Prague blob pricing.
+    if block.other.contains_key("requestsHash") {
+        return Err(WatcherError::UnsupportedHardfork(format!(
+            "block {} is past Cancun",
+            number
+        )));
+    }
+
+    Ok(BlockEnv {
+        number: rU256::from(number.as_u64()),
+        coinbase: r_address(block.author.unwrap_or_default()),
+        timestamp: r_u256(block.timestamp),
+        gas_limit: r_u256(block.gas_limit),
+        basefee: r_u256(block.base_fee_per_gas.unwrap_or_default()),
+        difficulty: r_u256(block.difficulty),
+        prevrandao: block.mix_hash.map(|hash| B256::from(hash.0)),
+        blob_excess_gas_and_price: block
+            .excess_blob_gas
+            .map(|excess| BlobExcessGasAndPrice::new(excess.as_u64())),
+    })
+}
+
+fn next_block_env<TX>(block: &Block<TX>) -> Result<BlockEnv, WatcherError> {
+    let mut env = block_env(block)?;
+
+    env.number += rU256::from(1);
+    env.timestamp += rU256::from(12);
+    env.basefee = r_u256(block.next_block_base_fee().unwrap_or_default());
+
+    Ok(env)
+}
+
+// Builds the same state diff trace_call returns, the values before the
+// transaction are the ones the fork loaded into its cache
+fn to_state_diff<ExtDB>(db: &CacheDB<ExtDB>, state: &State) -> StateDiff {
+    let mut state_diff = BTreeMap::new();
+
+    for (address, account) in state {
+        if !account.is_touched() {
+            continue;
+        }
+
+        let before = db
+            .accounts
+            .get(address)
+            .map(|account| account.info.clone())
+            .unwrap_or_default();
+        let after = &account.info;
+
+        let storage: BTreeMap<H256, Diff<H256>> = account
+            .storage
+            .iter()
+            .filter(|(_, slot)| slot.is_changed())
+            .map(|(slot, value)| {
+                let from = e_h256(value.previous_or_original_value);
+                let to = e_h256(value.present_value);
+                (e_h256(*slot), diff(Some(from), Some(to)))
+            })
+            .collect();
+
+        let account_diff = if account.is_selfdestructed() {
+            AccountDiff {
+                balance: Diff::Died(e_u256(before.balance)),
+                nonce: Diff::Died(U256::from(before.nonce)),
+                code: Diff::Same,
+                storage,
+            }
+        } else if account.is_created() {
+            let code = after
+                .code
+                .as_ref()
+                .map(|code| Bytes::from(code.original_bytes().to_vec()));
+
+            AccountDiff {
+                balance: Diff::Born(e_u256(after.balance)),
+                nonce: Diff::Born(U256::from(after.nonce)),
+                code: diff(None, code),
+                storage,
+            }
+        } else {
+            AccountDiff {
+                balance: diff(Some(e_u256(before.balance)), Some(e_u256(after.balance))),
+                nonce: diff(
+                    Some(U256::from(before.nonce)),
+                    Some(U256::from(after.nonce)),
+                ),
+                code: Diff::Same,
+                storage,
+            }
+        };
+
+        state_diff.insert(H160::from(address.0 .0), account_diff);
+    }
+
+    StateDiff(state_diff)
+}
+
+fn r_address(address: H160) -> rAddress {
+    rAddress::from(address.0)
+}
+
+fn r_u256(value: U256) -> rU256 {
+    rU256::from_limbs(value.0)
+}
+
+fn e_u256(value: rU256) -> U256 {
+    U256(*value.as_limbs())
+}
+
+fn e_h256(value: rU256) -> H256 {
+    H256::from(value.to_be_bytes::<32>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::ChangedType;
+    use revm::{db::EmptyDB, primitives::AccountInfo};
+
+    const ALICE: u64 = 0xa11ce;
+    const BOB: u64 = 0xb0b;
+    const CAROL: u64 = 0xca201;
+    // PUSH1 1 PUSH1 0 SSTORE STOP
+    const STORE: u64 = 0x5702e;
+    // PUSH1 0 PUSH1 0 REVERT
+    const REVERT: u64 = 0x2e7e27;
+
+    fn env_at(number: u64, timestamp: u64) -> BlockEnv {
+        BlockEnv {
+            number: rU256::from(number),
+            timestamp: rU256::from(timestamp),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn spec_id_follows_mainnet_hardforks() {
+        assert_eq!(spec_id(1, &env_at(0, 0)), SpecId::FRONTIER);
+        assert_eq!(spec_id(1, &env_at(12965000, 0)), SpecId::LONDON);
+        assert_eq!(spec_id(1, &env_at(15537394, 1663224162)), SpecId::MERGE);
+        assert_eq!(spec_id(1, &env_at(17034870, 1681338455)), SpecId::SHANGHAI);
+        assert_eq!(spec_id(1, &env_at(19426587, 1710338135)), SpecId::CANCUN);
+        assert_eq!(spec_id(1, &env_at(19426586, 1710338123)), SpecId::SHANGHAI);
+    }
+
+    #[test]
+    fn spec_id_of_other_chains_is_the_latest_known() {
+        assert_eq!(spec_id(10, &env_at(0, 0)), SpecId::CANCUN);
+    }
+
+    fn address(address: u64) -> H160 {
+        H160::from_low_u64_be(address)
+    }
+
+    fn cancun_env() -> BlockEnv {
+        BlockEnv {
+            basefee: rU256::from(10),
+            gas_limit: rU256::from(30_000_000),
+            ..env_at(19426587, 1710338135)
+        }
+    }
+
+    fn cancun_db() -> CacheDB<EmptyDB> {
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(
+            r_address(address(ALICE)),
+            AccountInfo::from_balance(rU256::from(10u64.pow(18))),
+        );
+        for (contract, code) in [
+            (STORE, vec![0x60, 0x01, 0x60, 0x00, 0x55, 0x00]),
+            (REVERT, vec![0x60, 0x00, 0x60, 0x00, 0xfd]),
+        ] {
+            let code = Bytecode::new_raw(code.into());
+            db.insert_account_info(
+                r_address(address(contract)),
+                AccountInfo::new(rU256::ZERO, 1, code.hash_slow(), code),
+            );
+        }
+        db
+    }
+
+    fn tx(hash: u64, from: u64, to: Option<u64>, value: u64) -> Transaction {
+        Transaction {
+            hash: H256::from_low_u64_be(hash),
+            from: address(from),
+            to: to.map(address),
+            value: U256::from(value),
+            gas: U256::from(100_000),
+            gas_price: Some(U256::from(20)),
+            chain_id: Some(U256::one()),
+            ..Default::default()
+        }
+    }
+
+    fn changed<T>(diff: &Diff<T>) -> (&T, &T) {
+        match diff {
+            Diff::Changed(ChangedType { from, to }) => (from, to),
+            _ => panic!("expected a change"),
+        }
+    }
+
+    #[test]
+    fn preceding_transactions_that_cant_be_executed_are_left_out() {
+        let metrics = Metrics::default();
+        let mut db = cancun_db();
+        let preceding = [
+            tx(1, ALICE, Some(STORE), 0),
+            // can't pay for its gas
+            tx(2, BOB, Some(STORE), 0),
+            tx(3, ALICE, Some(REVERT), 0),
+        ];
+        let transfer = tx(4, ALICE, Some(CAROL), 5);
+
+        commit_preceding(&mut db, &cancun_env(), &preceding, &transfer, &metrics);
+        let state = transact(&mut db, cancun_env(), &transfer).unwrap();
+        let state_diff = to_state_diff(&db, &state);
+
+        // the reverted transaction still paid for its gas and bumped the nonce
+        let alice = &state_diff.0[&address(ALICE)];
+        assert_eq!(changed(&alice.nonce), (&U256::from(2), &U256::from(3)));
+        assert_eq!(
+            changed(&state_diff.0[&address(CAROL)].balance),
+            (&U256::zero(), &U256::from(5))
+        );
+        assert_eq!(*metrics.errors.get("preceding_tx_failed").unwrap(), 1);
+    }
+
+    #[test]
+    fn replay_leaves_out_transactions_that_cant_be_executed() {
+        let metrics = Metrics::default();
+        let txs = [
+            tx(1, ALICE, Some(STORE), 0),
+            // can't pay for its gas
+            tx(2, BOB, Some(STORE), 0),
+            tx(3, ALICE, Some(REVERT), 0),
+            tx(4, ALICE, Some(CAROL), 5),
+        ];
+
+        let state_diffs = replay_txs(&mut cancun_db(), &cancun_env(), &txs, &metrics);
+
+        let hashes: Vec<_> = state_diffs.iter().map(|(hash, _)| *hash).collect();
+        assert_eq!(
+            hashes,
+            vec![
+                H256::from_low_u64_be(1),
+                H256::from_low_u64_be(3),
+                H256::from_low_u64_be(4)
+            ]
+        );
+        assert_eq!(*metrics.errors.get("simulation").unwrap(), 1);
+
+        // the reverted transaction still paid for its gas and bumped the nonce
+        let (_, reverted) = &state_diffs[1];
+        let alice = &reverted.0[&address(ALICE)];
+        assert_eq!(changed(&alice.nonce), (&U256::from(1), &U256::from(2)));
+    }
+
+    #[test]
+    fn state_diff_has_the_values_before_and_after_the_transaction() {
+        let metrics = Metrics::default();
+        let txs = [
+            tx(1, ALICE, Some(STORE), 0),
+            tx(2, ALICE, Some(CAROL), 5),
+            tx(3, ALICE, None, 7),
+        ];
+
+        let state_diffs = replay_txs(&mut cancun_db(), &cancun_env(), &txs, &metrics);
+        assert_eq!(state_diffs.len(), 3);
+
+        // 20 wei per gas: 10 of base fee and 10 of tip
+        let store = &state_diffs[0].1 .0;
+        let alice = &store[&address(ALICE)];
+        let (from, to) = changed(&alice.balance);
+        assert_eq!(*from, U256::exp10(18));
+        assert_eq!((*from - *to) % 20, U256::zero());
+        assert_eq!(changed(&alice.nonce), (&U256::zero(), &U256::one()));
+        assert_eq!(
+            changed(&store[&address(STORE)].storage[&H256::zero()]),
+            (&H256::zero(), &H256::from_low_u64_be(1))
+        );
+
+        // the transfer starts from the balance the first transaction left
+        let transfer = &state_diffs[1].1 .0;
+        assert_eq!(changed(&transfer[&address(ALICE)].balance).0, to);
+        assert_eq!(
+            changed(&transfer[&address(CAROL)].balance),
+            (&U256::zero(), &U256::from(5))
+        );
+
+        // a created account is born with the value it was sent
+        let (_, create) = &state_diffs[2];
+        let created: Vec<_> = create
+            .0
+            .values()
+            .filter(|account| matches!(account.balance, Diff::Born(_)))
+            .collect();
+        assert_eq!(created.len(), 1);
+        assert!(matches!(created[0].balance, Diff::Born(value) if value == U256::from(7)));
+    }
+
+    #[test]
+    fn blocks_past_cancun_are_not_simulated() {
+        let mut block = Block::<Transaction> {
+            number: Some(U64::from(22431084)),
+            ..Default::default()
+        };
+        assert!(block_env(&block).is_ok());
+
+        block.other.insert(
+            String::from("requestsHash"),
+            serde_json::Value::String(format!("{:?}", H256::zero())),
+        );
+        assert!(matches!(
+            block_env(&block),
+            Err(WatcherError::UnsupportedHardfork(_))
+        ));
+    }
+}
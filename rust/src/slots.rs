@@ -0,0 +1,323 @@
+use anyhow::{anyhow, Context, Result};
+use dashmap::DashMap;
+use ethers::{
+    abi,
+    providers::{
+        call_raw::{spoof, RawCall},
+        Middleware,
+    },
+    types::{
+        transaction::eip2718::TypedTransaction, Address, Bytes, TransactionRequest, H256, U256,
+    },
+    utils::keccak256,
+};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+// balanceOf(address)
+const BALANCE_OF_SELECTOR: [u8; 4] = [0x70, 0xa0, 0x82, 0x31];
+
+// Any address works as the probe holder, its balance slot is overridden on every call
+const PROBE_HOLDER: Address = Address::repeat_byte(0x51);
+const PROBE_VALUE: u64 = 0x1337_c0ffee;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageLayout {
+    // mapping(address => uint256): keccak256(abi.encode(holder, index))
+    Solidity,
+    // HashMap[address, uint256]: keccak256(abi.encode(index, holder))
+    Vyper,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BalanceSlot {
+    pub index: U256,
+    pub layout: StorageLayout,
+}
+
+impl BalanceSlot {
+    pub fn slot_of(&self, holder: Address) -> H256 {
+        let holder = abi::Token::Address(holder);
+        let index = abi::Token::Uint(self.index);
+
+        let encoded = match self.layout {
+            StorageLayout::Solidity => abi::encode(&[holder, index]),
+            StorageLayout::Vyper => abi::encode(&[index, holder]),
+        };
+
+        H256::from(keccak256(encoded))
+    }
+}
+
+// Balance slots are kept next to the pool checkpoint, so tokens are only probed once
+pub fn slots_path(checkpoint_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.slots.json", checkpoint_path))
+}
+
+// Finds and caches the storage slot of the balanceOf mapping for ERC20 tokens.
+//
+// Each candidate slot is written with a known value through an eth_call state override,
+// and the candidate is accepted when balanceOf returns that value.
+// Proxy tokens work the same way, because the implementation reads the proxy's storage.
+pub struct SlotDiscovery<M> {
+    provider: Arc<M>,
+    cache: DashMap<Address, BalanceSlot>,
+    max_index: u64,
+}
+
+impl<M: Middleware> SlotDiscovery<M> {
+    pub fn new(provider: Arc<M>, max_index: u64) -> Self {
+        Self {
+            provider,
+            cache: DashMap::new(),
+            max_index,
+        }
+    }
+
+    // Slots written by `save`, nothing to load if the file doesn't exist yet
+    pub fn load(&self, path: &Path) -> Result<()> {
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let slots: BTreeMap<Address, BalanceSlot> = serde_json::from_str(&contents)
+            .with_context(|| format!("{}: invalid balance slots", path.display()))?;
+
+        for (token, slot) in slots {
+            self.cache.insert(token, slot);
+        }
+
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let slots: BTreeMap<Address, BalanceSlot> = self
+            .cache
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value()))
+            .collect();
+
+        std::fs::write(path, serde_json::to_string_pretty(&slots)?)
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    pub fn cached(&self, token: &Address) -> Option<BalanceSlot> {
+        self.cache.get(token).map(|slot| *slot.value())
+    }
+
+    pub async fn balance_slot(&self, token: Address) -> Result<BalanceSlot> {
+        if let Some(slot) = self.cached(&token) {
+            return Ok(slot);
+        }
+
+        // a call can revert for a wrong candidate (e.g. an overflow on a garbage value), so a
+        // failed probe only rules out that slot
+        let mut last_error = None;
+
+        for index in 0..self.max_index {
+            for layout in [StorageLayout::Solidity, StorageLayout::Vyper] {
+                let slot = BalanceSlot {
+                    index: U256::from(index),
+                    layout,
+                };
+
+                match self.probe(token, slot).await {
+                    Ok(true) => {
+                        info!(
+                            "Balance slot of {:?}: index {} ({:?})",
+                            token, slot.index, slot.layout
+                        );
+                        self.cache.insert(token, slot);
+                        return Ok(slot);
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        debug!(
+                            "Probing index {} ({:?}) of {:?} failed: {}",
+                            slot.index, slot.layout, token, e
+                        );
+                        last_error = Some(e);
+                    }
+                }
+            }
+        }
+
+        let error = anyhow!(
+            "balanceOf slot of {:?} not found in the first {} slots",
+            token,
+            self.max_index
+        );
+        Err(match last_error {
+            Some(e) => e.context(error),
+            None => error,
+        })
+    }
+
+    async fn probe(&self, token: Address, slot: BalanceSlot) -> Result<bool> {
+        let probe_value = U256::from(PROBE_VALUE);
+
+        let mut value = [0u8; 32];
+        probe_value.to_big_endian(&mut value);
+
+        let state = spoof::storage(token, slot.slot_of(PROBE_HOLDER), H256::from(value));
+
+        let mut calldata = BALANCE_OF_SELECTOR.to_vec();
+        calldata.extend(abi::encode(&[abi::Token::Address(PROBE_HOLDER)]));
+
+        let tx: TypedTransaction = TransactionRequest::new()
+            .to(token)
+            .data(Bytes::from(calldata))
+            .into();
+
+        let output = self.provider.provider().call_raw(&tx).state(&state).await?;
+
+        if output.len() < 32 {
+            return Ok(false);
+        }
+
+        Ok(U256::from_big_endian(&output[..32]) == probe_value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::providers::{JsonRpcError, MockProvider, MockResponse, Provider};
+    use std::str::FromStr;
+
+    const WETH: &str = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2";
+
+    fn slot_discovery() -> SlotDiscovery<Provider<MockProvider>> {
+        SlotDiscovery::new(Arc::new(Provider::new(MockProvider::new())), 100)
+    }
+
+    // eth_call results of consecutive probes, in probing order
+    fn probed(results: Vec<MockResponse>) -> SlotDiscovery<Provider<MockProvider>> {
+        let mock = MockProvider::new();
+        // the mock answers from the back
+        for result in results.into_iter().rev() {
+            mock.push_response(result);
+        }
+        SlotDiscovery::new(Arc::new(Provider::new(mock)), 2)
+    }
+
+    fn returns(value: u64) -> MockResponse {
+        let mut output = [0u8; 32];
+        U256::from(value).to_big_endian(&mut output);
+        MockResponse::Value(serde_json::to_value(Bytes::from(output.to_vec())).unwrap())
+    }
+
+    fn reverts() -> MockResponse {
+        MockResponse::Error(JsonRpcError {
+            code: 3,
+            message: String::from("execution reverted"),
+            data: None,
+        })
+    }
+
+    #[test]
+    fn solidity_slot_of_weth_is_the_mapping_at_index_3() {
+        let pool = Address::repeat_byte(0xaa);
+        let slot = BalanceSlot {
+            index: U256::from(3),
+            layout: StorageLayout::Solidity,
+        };
+
+        let expected = H256::from(keccak256(abi::encode(&[
+            abi::Token::Address(pool),
+            abi::Token::Uint(U256::from(3)),
+        ])));
+        assert_eq!(slot.slot_of(pool), expected);
+
+        let vyper = BalanceSlot {
+            layout: StorageLayout::Vyper,
+            ..slot
+        };
+        assert_ne!(vyper.slot_of(pool), expected);
+    }
+
+    #[tokio::test]
+    async fn probing_goes_on_past_failed_calls() {
+        let weth = Address::from_str(WETH).unwrap();
+        let discovery = probed(vec![
+            // index 0
+            reverts(),
+            returns(0),
+            // index 1
+            MockResponse::Value(serde_json::to_value(Bytes::new()).unwrap()),
+            returns(PROBE_VALUE),
+        ]);
+
+        let slot = discovery.balance_slot(weth).await.unwrap();
+        assert_eq!(
+            slot,
+            BalanceSlot {
+                index: U256::from(1),
+                layout: StorageLayout::Vyper,
+            }
+        );
+
+        // cached, the node isn't asked again
+        assert_eq!(discovery.balance_slot(weth).await.unwrap(), slot);
+    }
+
+    #[tokio::test]
+    async fn failed_probes_are_reported_when_no_slot_matches() {
+        let weth = Address::from_str(WETH).unwrap();
+        let discovery = probed(vec![returns(0), reverts(), returns(1), reverts()]);
+
+        let e = discovery.balance_slot(weth).await.unwrap_err();
+        assert!(e.to_string().contains("not found in the first 2 slots"));
+        assert!(format!("{:#}", e).contains("execution reverted"));
+        assert_eq!(discovery.cached(&weth), None);
+    }
+
+    #[test]
+    fn saved_slots_are_loaded_back() {
+        let path = std::env::temp_dir().join(format!("slots-{}.json", std::process::id()));
+        let weth = Address::repeat_byte(0x01);
+        let crv = Address::repeat_byte(0x02);
+
+        let saved = slot_discovery();
+        saved.cache.insert(
+            weth,
+            BalanceSlot {
+                index: U256::from(3),
+                layout: StorageLayout::Solidity,
+            },
+        );
+        saved.cache.insert(
+            crv,
+            BalanceSlot {
+                index: U256::from(20),
+                layout: StorageLayout::Vyper,
+            },
+        );
+        saved.save(&path).unwrap();
+
+        let loaded = slot_discovery();
+        loaded.load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.cached(&weth), saved.cached(&weth));
+        assert_eq!(loaded.cached(&crv), saved.cached(&crv));
+    }
+
+    #[test]
+    fn missing_file_loads_nothing() {
+        let loaded = slot_discovery();
+        loaded
+            .load(Path::new("/nonexistent/checkpoint.json.slots.json"))
+            .unwrap();
+
+        assert_eq!(loaded.cached(&Address::repeat_byte(0x01)), None);
+    }
+}
@@ -1,253 +1,1259 @@
 use anyhow::{anyhow, Result};
-use cfmms::{
-    checkpoint::sync_pools_from_checkpoint,
-    dex::{Dex, DexVariant},
-    pool::Pool,
-    sync::sync_pairs,
-};
+use cfmms::pool::Pool;
+use clap::ValueEnum;
 use dashmap::DashMap;
 use ethers::{
-    abi,
-    providers::{Provider, Ws},
-    types::{Address, BlockNumber, Diff, TraceType, Transaction, H160, H256, U256, U64},
-    utils::keccak256,
+    providers::Middleware,
+    types::{
+        AccountDiff, Address, Block, BlockNumber, Diff, Transaction, H160, H256, I256, U256, U64,
+    },
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+use std::{
+    collections::{BTreeMap, HashSet},
+    ops::RangeInclusive,
+    path::PathBuf,
+    sync::{Arc, Mutex},
 };
-use ethers_providers::Middleware;
-use log::info;
-use std::{path::Path, str::FromStr, sync::Arc};
-use tokio::sync::broadcast::{self, Sender};
-use tokio::task::JoinSet;
-use tokio_stream::StreamExt;
+use tokio::sync::Semaphore;
+use tokio::task::{JoinError, JoinSet};
 
-use crate::utils::calculate_next_block_base_fee;
+use crate::backend::{new_backend, TraceBackend};
+use crate::chain::ChainTracker;
+use crate::channel::{event_channel, EventReceiver};
+use crate::config::{LagPolicy, TraceTarget, WatcherConfig};
+use crate::connection::{stream_headers, stream_pending_txs, Connector};
+use crate::dedup::SeenCache;
+use crate::error::WatcherError;
+use crate::mempool::{Mempool, MempoolUpdate};
+use crate::metrics::Metrics;
+use crate::pools::sync_pools;
+use crate::propagation::{Propagation, Sighting};
+use crate::recording::{play, Recorder, RecorderHandle};
+use crate::scoring::{InclusionScore, TipBook};
+use crate::slots::{slots_path, BalanceSlot, SlotDiscovery};
+use crate::utils::{with_jitter, TxFees};
 
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct NewBlock {
     pub number: U64,
+    pub hash: H256,
+    pub parent_hash: H256,
+    // fee recipient, usually the builder
+    pub miner: Address,
     pub gas_used: U256,
     pub gas_limit: U256,
     pub base_fee_per_gas: U256,
     pub timestamp: U256,
+    // EIP-4844, None before Cancun
+    pub excess_blob_gas: Option<U256>,
+    pub blob_gas_used: Option<U256>,
+}
+
+impl TryFrom<Block<H256>> for NewBlock {
+    type Error = WatcherError;
+
+    // Headers from newHeads always carry a number, hash and miner
+    fn try_from(block: Block<H256>) -> Result<Self, Self::Error> {
+        let missing = |field: &str| {
+            WatcherError::InvalidHeader(format!("{} is missing: {:?}", field, block.hash))
+        };
+
+        Ok(NewBlock {
+            number: block.number.ok_or_else(|| missing("number"))?,
+            hash: block.hash.ok_or_else(|| missing("hash"))?,
+            parent_hash: block.parent_hash,
+            miner: block.author.ok_or_else(|| missing("miner"))?,
+            gas_used: block.gas_used,
+            gas_limit: block.gas_limit,
+            base_fee_per_gas: block.base_fee_per_gas.unwrap_or_default(),
+            timestamp: block.timestamp,
+            excess_blob_gas: block.excess_blob_gas,
+            blob_gas_used: block.blob_gas_used,
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum Event {
-    NewBlock(NewBlock),
-    Transaction(Transaction),
+    NewBlock {
+        block: Box<NewBlock>,
+        received: Received,
+    },
+    Transaction {
+        tx: Box<Transaction>,
+        received: Received,
+    },
+    // a subscription of one of the nodes was reestablished, events in between were missed
+    Reconnected {
+        node: usize,
+        subscription: Subscription,
+    },
+    // blocks orphaned by a reorg, followed by NewBlock for the new tip
+    Reorg {
+        dropped: Vec<NewBlock>,
+        new: Vec<NewBlock>,
+    },
 }
 
-async fn trace_state_diff(
-    provider: Arc<Provider<Ws>>,
-    tx: &Transaction,
-    block_number: U64,
-    pools: &DashMap<H160, Pool>,
-    target_address: String,
-) -> Result<()> {
-    info!(
-        "Tx #{} received. Checking if it touches: {}",
-        tx.hash, target_address
-    );
+// Which node a header or pending transaction came from, and when
+#[derive(Debug, Clone, Copy)]
+pub struct Received {
+    // index into the configured nodes
+    pub node: usize,
+    pub at: SystemTime,
+}
 
-    let target_address: Address = target_address.parse().unwrap();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subscription {
+    NewHeads,
+    PendingTransactions,
+}
 
-    let state_diff = provider
-        .trace_call(
-            tx,
-            vec![TraceType::StateDiff],
-            Some(BlockNumber::from(block_number)),
-        )
-        .await?
-        .state_diff
-        .ok_or(anyhow!("state diff does not exist"))?
-        .0;
-
-    let touched_pools: Vec<Pool> = state_diff
-        .keys()
-        .filter_map(|addr| pools.get(addr).map(|p| (*p.value()).clone()))
-        .filter(|p| match p {
-            Pool::UniswapV2(pool) => vec![pool.token_a, pool.token_b].contains(&target_address),
-            Pool::UniswapV3(pool) => vec![pool.token_a, pool.token_b].contains(&target_address),
-        })
-        .collect();
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SwapDirection {
+    // the pool's <target_token> balance increased: <target_token> -> token
+    TargetToToken,
+    // the pool's <target_token> balance decreased: token -> <target_token>
+    TokenToTarget,
+}
+
+// A token to watch, with the storage slot of its balanceOf mapping
+#[derive(Debug, Clone, Copy)]
+pub struct TargetToken {
+    pub address: Address,
+    pub balance_slot: BalanceSlot,
+}
+
+// A pool whose <target_token> balance was changed by a transaction
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TouchedPool {
+    pub tx_hash: H256,
+    pub pool: H160,
+    pub token: H160,
+    pub balance_before: U256,
+    pub balance_after: U256,
+    pub direction: SwapDirection,
+    // balance_after - balance_before, as seen from the pool
+    pub amount: I256,
+    // pending transactions only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<InclusionScore>,
+    // pending transactions only, the nodes that delivered it before it was traced
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub first_seen: Vec<Sighting>,
+}
+
+// How detections are reported, they are always logged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Log,
+    // one JSON object per line on stdout
+    Json,
+}
+
+impl OutputFormat {
+    pub fn emit(&self, detections: &[TouchedPool]) -> Result<()> {
+        if let OutputFormat::Json = self {
+            for detection in detections {
+                println!("{}", serde_json::to_string(detection)?);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Turns a storage diff into (before, after) values.
+// A slot written for the first time was zero before, and a cleared slot is zero after.
+fn balance_change(diff: &Diff<H256>) -> Option<(U256, U256)> {
+    match diff {
+        Diff::Same => None,
+        Diff::Born(to) => Some((U256::zero(), U256::from(to.to_fixed_bytes()))),
+        Diff::Died(from) => Some((U256::from(from.to_fixed_bytes()), U256::zero())),
+        Diff::Changed(c) => Some((
+            U256::from(c.from.to_fixed_bytes()),
+            U256::from(c.to.to_fixed_bytes()),
+        )),
+    }
+}
 
-    if touched_pools.is_empty() {
-        return Ok(());
+// Direction and signed amount of a swap from the pool's <target_token> balance before and after
+fn swap_direction(from: U256, to: U256) -> Option<(SwapDirection, I256)> {
+    // if to > from, the balance of pool's <target_token> has increased
+    // thus, the transaction was a call to swap: <target_token> -> token
+    // if to < from, <target_token> left the pool: token -> <target_token>
+    if to > from {
+        Some((SwapDirection::TargetToToken, I256::from_raw(to - from)))
+    } else if to < from {
+        Some((SwapDirection::TokenToTarget, -I256::from_raw(from - to)))
+    } else {
+        None
     }
+}
 
-    let target_storage = &state_diff
-        .get(&target_address)
-        .ok_or(anyhow!("no target storage"))?
-        .storage;
+// Finds the pools whose <target_token> balances were changed in a state diff
+pub fn analyze_state_diff(
+    tx_hash: H256,
+    state_diff: &BTreeMap<H160, AccountDiff>,
+    pools: &DashMap<H160, Pool>,
+    targets: &[TargetToken],
+) -> Result<Vec<TouchedPool>, WatcherError> {
+    let mut detections = Vec::new();
+    let mut missing_storage = None;
+
+    for target in targets {
+        let target_address = target.address;
 
-    for pool in &touched_pools {
-        let slot = H256::from(keccak256(abi::encode(&[
-            abi::Token::Address(pool.address()),
-            abi::Token::Uint(U256::from(3)),
-        ])));
+        let touched_pools: Vec<Pool> = state_diff
+            .keys()
+            .filter_map(|addr| pools.get(addr).map(|p| *p.value()))
+            .filter(|p| match p {
+                Pool::UniswapV2(pool) => [pool.token_a, pool.token_b].contains(&target_address),
+                Pool::UniswapV3(pool) => [pool.token_a, pool.token_b].contains(&target_address),
+            })
+            .collect();
 
-        if let Some(Diff::Changed(c)) = target_storage.get(&slot) {
-            let from = U256::from(c.from.to_fixed_bytes());
-            let to = U256::from(c.to.to_fixed_bytes());
+        if touched_pools.is_empty() {
+            continue;
+        }
+
+        let target_storage = match state_diff.get(&target_address) {
+            Some(account) => &account.storage,
+            None => {
+                missing_storage = Some(target_address);
+                continue;
+            }
+        };
+
+        for pool in &touched_pools {
+            let slot = target.balance_slot.slot_of(pool.address());
+
+            if let Some((from, to)) = target_storage.get(&slot).and_then(balance_change) {
+                let Some((direction, amount)) = swap_direction(from, to) else {
+                    continue;
+                };
 
-            if to > from {
-                // if to > from, the balance of pool's <target_token> has increased
-                // thus, the transaction was a call to swap: <target_token> -> token
                 info!(
-                    "(Tx #{}) Balance change: {} -> {} @ Pool {}",
-                    tx.hash,
+                    "(Tx #{}) Balance change: {} -> {} ({:?}) @ Pool {}",
+                    tx_hash,
                     from,
                     to,
+                    direction,
                     pool.address()
                 );
+
+                detections.push(TouchedPool {
+                    tx_hash,
+                    pool: pool.address(),
+                    token: target_address,
+                    balance_before: from,
+                    balance_after: to,
+                    direction,
+                    amount,
+                    score: None,
+                    first_seen: Vec::new(),
+                });
             }
         }
     }
 
-    Ok(())
+    if let (true, Some(target_address)) = (detections.is_empty(), missing_storage) {
+        return Err(WatcherError::MissingTargetStorage(target_address));
+    }
+
+    Ok(detections)
 }
 
-pub async fn mempool_watching(target_address: String) -> Result<()> {
-    let wss_url: String = std::env::var("WSS_URL").unwrap();
-    let provider = Provider::<Ws>::connect(wss_url).await?;
-    let provider = Arc::new(provider);
+// Traces `tx` on top of `block`, after the transactions in `ahead` when there are any
+pub async fn trace_state_diff(
+    backend: &dyn TraceBackend,
+    tx: &Transaction,
+    ahead: &[Transaction],
+    block: BlockNumber,
+    pools: &DashMap<H160, Pool>,
+    targets: &[TargetToken],
+) -> Result<Vec<TouchedPool>, WatcherError> {
+    info!("Tx #{} received. Checking if it touches targets", tx.hash);
 
-    // Step #1: Using cfmms-rs to sync all pools created on Uniswap V3
-    let checkpoint_path = ".cfmms-checkpoint.json";
-    let checkpoint_exists = Path::new(checkpoint_path).exists();
+    let state_diff = if ahead.is_empty() {
+        backend.trace_call(tx, block).await?
+    } else {
+        let mut txs = ahead.to_vec();
+        txs.push(tx.clone());
+        backend.trace_call_many(&txs, block).await?
+    };
 
-    let pools = DashMap::new();
+    analyze_state_diff(tx.hash, &state_diff.0, pools, targets)
+}
 
-    let dexes_data = [(
-        // Uniswap v3
-        "0x1F98431c8aD98523631AE4a59f267346ea31F984",
-        DexVariant::UniswapV3,
-        12369621u64,
-    )];
-    let dexes: Vec<_> = dexes_data
-        .into_iter()
-        .map(|(address, variant, number)| {
-            Dex::new(H160::from_str(address).unwrap(), variant, number, Some(300))
-        })
-        .collect();
+// Traces `tx` alone to find the pools it touches, then again behind the pending transactions
+// `ahead` picks for those pools, if there are any
+pub async fn trace_bundle(
+    backend: &dyn TraceBackend,
+    tx: &Transaction,
+    block: BlockNumber,
+    pools: &DashMap<H160, Pool>,
+    targets: &[TargetToken],
+    ahead: impl FnOnce(&[H160]) -> Vec<Transaction>,
+) -> Result<Vec<TouchedPool>, WatcherError> {
+    let touched = trace_state_diff(backend, tx, &[], block, pools, targets).await?;
+    if touched.is_empty() {
+        return Ok(touched);
+    }
 
-    let pools_vec = if checkpoint_exists {
-        let (_, pools_vec) =
-            sync_pools_from_checkpoint(checkpoint_path, 100000, provider.clone()).await?;
-        pools_vec
-    } else {
-        sync_pairs(dexes.clone(), provider.clone(), Some(checkpoint_path)).await?
-    };
+    let touched_pools: Vec<_> = touched.iter().map(|detection| detection.pool).collect();
+    let ahead = ahead(&touched_pools);
+    if ahead.is_empty() {
+        return Ok(touched);
+    }
 
-    for pool in pools_vec {
-        pools.insert(pool.address(), pool);
+    trace_state_diff(backend, tx, &ahead, block, pools, targets).await
+}
+
+// Traces a single transaction: mined transactions are replayed in their block,
+// pending ones are traced on top of the latest block
+pub async fn trace_transaction<M: Middleware + 'static>(
+    provider: Arc<M>,
+    backend: &dyn TraceBackend,
+    tx_hash: H256,
+    pools: &DashMap<H160, Pool>,
+    targets: &[TargetToken],
+) -> Result<Vec<TouchedPool>>
+where
+    M::Error: 'static,
+{
+    let tx = provider
+        .get_transaction(tx_hash)
+        .await?
+        .ok_or(anyhow!("transaction not found: {:?}", tx_hash))?;
+
+    if tx.block_number.is_none() {
+        let block_number = provider.get_block_number().await?;
+        let block = BlockNumber::from(block_number);
+        return Ok(trace_state_diff(backend, &tx, &[], block, pools, targets).await?);
     }
 
-    info!("Uniswap V3 pools synced: {}", pools.len());
+    let state_diff = backend.trace_replay(tx_hash).await?;
 
-    // Step #2: Stream data asynchronously
-    let (event_sender, _): (Sender<Event>, _) = broadcast::channel(512);
+    Ok(analyze_state_diff(tx_hash, &state_diff.0, pools, targets)?)
+}
 
-    let mut set = JoinSet::new();
+// Transactions mined in a range of blocks, and the pools they touched
+#[derive(Debug, Default)]
+pub struct ReplayedBlocks {
+    pub mined: HashSet<H256>,
+    pub detections: Vec<TouchedPool>,
+}
 
-    // Stream new headers
-    {
-        let provider = provider.clone();
-        let event_sender = event_sender.clone();
-
-        set.spawn(async move {
-            let stream = provider.subscribe_blocks().await.unwrap();
-            let mut stream = stream.filter_map(|block| match block.number {
-                Some(number) => Some(NewBlock {
-                    number,
-                    gas_used: block.gas_used,
-                    gas_limit: block.gas_limit,
-                    base_fee_per_gas: block.base_fee_per_gas.unwrap_or_default(),
-                    timestamp: block.timestamp,
-                }),
-                None => None,
-            });
+// Runs the analysis over every transaction mined in [from_block, to_block].
+// Blocks are replayed with a single request when the backend supports it.
+pub async fn replay_blocks<M: Middleware + 'static>(
+    provider: Arc<M>,
+    backend: &dyn TraceBackend,
+    blocks: RangeInclusive<u64>,
+    pools: &DashMap<H160, Pool>,
+    targets: &[TargetToken],
+    output: OutputFormat,
+    metrics: &Metrics,
+) -> Result<ReplayedBlocks>
+where
+    M::Error: 'static,
+{
+    let mut replayed = ReplayedBlocks::default();
+    let mut batch = true;
+
+    for number in blocks {
+        let block = provider
+            .get_block(number)
+            .await?
+            .ok_or(anyhow!("block not found: {}", number))?;
+
+        info!(
+            "Replaying block #{} ({} txs)",
+            number,
+            block.transactions.len()
+        );
 
-            while let Some(block) = stream.next().await {
-                match event_sender.send(Event::NewBlock(block)) {
-                    Ok(_) => {}
-                    Err(_) => {}
+        let state_diffs = if batch {
+            match backend.trace_replay_block(number).await {
+                Ok(state_diffs) => Some(state_diffs),
+                Err(WatcherError::TraceUnsupported(e)) => {
+                    info!("Replaying transaction by transaction: {}", e);
+                    batch = false;
+                    None
+                }
+                // the transactions are replayed one by one for this block only
+                Err(e) => {
+                    metrics.record_error(&e);
+                    warn!("Block #{}: {}", number, e);
+                    None
                 }
             }
-        });
-    }
+        } else {
+            None
+        };
 
-    // Stream pending transactions
-    {
-        let provider = provider.clone();
-        let event_sender = event_sender.clone();
+        let state_diffs = match state_diffs {
+            Some(state_diffs) => state_diffs,
+            None => {
+                let mut state_diffs = Vec::new();
+                for tx_hash in &block.transactions {
+                    match backend.trace_replay(*tx_hash).await {
+                        Ok(state_diff) => state_diffs.push((*tx_hash, state_diff)),
+                        Err(e) => {
+                            metrics.record_error(&e);
+                            warn!("Tx #{:?}: {}", tx_hash, e);
+                        }
+                    }
+                }
+                state_diffs
+            }
+        };
 
-        set.spawn(async move {
-            let stream = provider.subscribe_pending_txs().await.unwrap();
-            let mut stream = stream.transactions_unordered(256).fuse();
+        // transactions that failed to replay are left out, their detections stay unresolved
+        replayed
+            .mined
+            .extend(state_diffs.iter().map(|(tx_hash, _)| *tx_hash));
 
-            while let Some(result) = stream.next().await {
-                match result {
-                    Ok(tx) => match event_sender.send(Event::Transaction(tx)) {
-                        Ok(_) => {}
-                        Err(_) => {}
-                    },
-                    Err(_) => {}
-                };
+        for (tx_hash, state_diff) in state_diffs {
+            // a touched pool without target storage is not a reason to stop the replay
+            match analyze_state_diff(tx_hash, &state_diff.0, pools, targets) {
+                Ok(touched) => {
+                    output.emit(&touched)?;
+                    replayed.detections.extend(touched);
+                }
+                Err(e) => {
+                    metrics.record_error(&e);
+                    warn!("Tx #{:?}: {}", tx_hash, e);
+                }
             }
+        }
+    }
+
+    Ok(replayed)
+}
+
+pub async fn discover_targets<M: Middleware + 'static>(
+    provider: Arc<M>,
+    tokens: &[Address],
+    checkpoint_path: &str,
+) -> Result<Vec<TargetToken>>
+where
+    M::Error: 'static,
+{
+    // Find where each <target_token> keeps its balances, so pool balances can be read from the state diff
+    let slot_discovery = SlotDiscovery::new(provider, 100);
+
+    // a broken cache is probed again, and replaced once new slots are found
+    let path = slots_path(checkpoint_path);
+    if let Err(e) = slot_discovery.load(&path) {
+        warn!("Ignoring cached balance slots: {:#}", e);
+    }
+
+    let mut discovered = false;
+    let mut targets = Vec::new();
+    for address in tokens {
+        discovered |= slot_discovery.cached(address).is_none();
+
+        let balance_slot = slot_discovery.balance_slot(*address).await?;
+        targets.push(TargetToken {
+            address: *address,
+            balance_slot,
         });
     }
 
-    // Event handler
-    {
-        let mut event_receiver = event_sender.subscribe();
+    if discovered {
+        slot_discovery.save(&path)?;
+    }
+
+    Ok(targets)
+}
+
+// The event handler and what it works with, shared by live watching and playback
+pub struct Watcher<M> {
+    provider: Arc<M>,
+    config: WatcherConfig,
+    pools: Arc<DashMap<H160, Pool>>,
+    targets: Arc<Vec<TargetToken>>,
+    backend: Arc<dyn TraceBackend>,
+    pub metrics: Arc<Metrics>,
+    // pending transactions by (sender, nonce) and the pools they swap in
+    pub mempool: Arc<Mutex<Mempool>>,
+}
+
+impl<M> Watcher<M>
+where
+    M: Middleware + 'static,
+    M::Error: 'static,
+{
+    pub async fn new(provider: Arc<M>, config: WatcherConfig) -> Result<Self> {
+        // Step #1: Using cfmms-rs to sync all pools created on the configured dexes
+        let pools = sync_pools(
+            provider.clone(),
+            &config.dex_specs(),
+            &config.checkpoint.path,
+            config.checkpoint.step,
+        )
+        .await?;
+
+        let targets = discover_targets(
+            provider.clone(),
+            &config.target_tokens,
+            &config.checkpoint.path,
+        )
+        .await?;
+        let metrics = Arc::new(Metrics::default());
+        let backend = new_backend(config.tracing.backend, provider.clone(), metrics.clone());
+
+        Ok(Self {
+            provider,
+            config,
+            pools: Arc::new(pools),
+            targets: Arc::new(targets),
+            backend,
+            metrics,
+            mempool: Arc::new(Mutex::new(Mempool::default())),
+        })
+    }
+
+    // Handles events until every sender is gone, `nodes` names the nodes they come from
+    pub async fn run(
+        self,
+        mut event_receiver: EventReceiver,
+        nodes: Vec<String>,
+        recorder: Option<RecorderHandle>,
+        output: OutputFormat,
+    ) -> Result<()> {
+        let Watcher {
+            provider,
+            config,
+            pools,
+            targets,
+            backend,
+            metrics,
+            mempool,
+        } = self;
+
+        // first-seen times of pending transactions on each node
+        let propagation = Arc::new(Mutex::new(Propagation::new(
+            nodes,
+            Duration::from_secs(config.dedup.ttl_secs),
+            config.dedup.max_entries,
+        )));
+
+        // Report metrics periodically, stops with the handler
+        let mut reporter = JoinSet::new();
+        {
+            let metrics = metrics.clone();
+            let propagation = propagation.clone();
+
+            reporter.spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(60));
+                interval.tick().await;
+
+                loop {
+                    interval.tick().await;
+                    metrics.report();
+                    propagation.lock().unwrap().report();
+                }
+            });
+        }
+
+        let tracer = Tracer {
+            backend,
+            pools,
+            targets,
+            mempool: mempool.clone(),
+            propagation: propagation.clone(),
+            metrics: metrics.clone(),
+            semaphore: Arc::new(Semaphore::new(config.tracing.max_in_flight)),
+            timeout: Duration::from_millis(config.tracing.timeout_ms),
+            target: config.tracing.target,
+            bundle_size: config.tracing.bundle_size,
+            output,
+        };
+        let base_fee_jitter = config.tracing.base_fee_jitter;
+        let max_age_blocks = config.mempool.max_age_blocks;
+        let mut seen = SeenCache::new(
+            Duration::from_secs(config.dedup.ttl_secs),
+            config.dedup.max_entries,
+        );
+
+        let mut new_block = NewBlock::default();
+        // tips of the pending transactions competing for the next block
+        let mut tip_book = Arc::new(Mutex::new(TipBook::default()));
+        // traces of pending transactions, cancelled when a new block arrives
+        let mut in_flight = JoinSet::new();
+        // transactions to trace again once the header that caused a reorg arrives
+        let mut retrace = HashSet::new();
+        // Ctrl-C stops the handler, so the recording is completed before exiting
+        let interrupted = tokio::signal::ctrl_c();
+        tokio::pin!(interrupted);
+
+        loop {
+            let event = tokio::select! {
+                Some(joined) = in_flight.join_next(), if !in_flight.is_empty() => {
+                    check_joined(joined, &metrics);
+                    continue;
+                }
+                _ = &mut interrupted => {
+                    info!("Interrupted, dropping {} traces", in_flight.len());
+                    in_flight.abort_all();
+                    break;
+                }
+                event = event_receiver.recv() => event,
+            };
+
+            if let (Some(recorder), Some(event)) = (recorder.as_ref(), &event) {
+                recorder.record(event);
+            }
+
+            match event {
+                Some(event) => match event {
+                    Event::NewBlock { block, .. } => {
+                        new_block = *block;
+                        info!("{:?}", new_block);
+
+                        tip_book = Arc::new(Mutex::new(TipBook::new(&new_block)));
 
-        set.spawn(async move {
-            let mut new_block = NewBlock::default();
+                        // traces against the previous block are stale now
+                        if !in_flight.is_empty() {
+                            info!("Dropping {} stale traces", in_flight.len());
+                            in_flight.abort_all();
+                        }
+
+                        let expired = mempool
+                            .lock()
+                            .unwrap()
+                            .expire(new_block.number.saturating_sub(U64::from(max_age_blocks)));
+                        if expired > 0 {
+                            info!("Expired {} pending transactions", expired);
+                        }
+
+                        if !retrace.is_empty() {
+                            info!(
+                                "Tracing {} transactions again on the new tip",
+                                retrace.len()
+                            );
+                        }
+                        for tx_hash in retrace.drain() {
+                            let Some(tx) = mempool
+                                .lock()
+                                .unwrap()
+                                .get(&tx_hash)
+                                .map(|pending| pending.tx.clone())
+                            else {
+                                continue;
+                            };
 
-            loop {
-                match event_receiver.recv().await {
-                    Ok(event) => match event {
-                        Event::NewBlock(block) => {
-                            new_block = block;
-                            info!("{:?}", new_block);
+                            let fees = TxFees::of(&tx);
+                            if pays_base_fees(&tip_book.lock().unwrap(), &fees, base_fee_jitter) {
+                                tip_book.lock().unwrap().insert(&tx, &fees);
+                                in_flight.spawn(tracer.clone().trace(
+                                    tx,
+                                    fees,
+                                    tip_book.clone(),
+                                    new_block.number,
+                                ));
+                            }
                         }
-                        Event::Transaction(tx) => {
-                            if new_block.number != U64::zero() {
-                                let next_base_fee = calculate_next_block_base_fee(
-                                    new_block.gas_used,
-                                    new_block.gas_limit,
-                                    new_block.base_fee_per_gas,
+
+                        // the header doesn't list its transactions, evict them once the block is fetched
+                        let provider = provider.clone();
+                        let mempool = mempool.clone();
+                        let metrics = metrics.clone();
+                        let block_hash = new_block.hash;
+                        let block_number = new_block.number;
+
+                        tokio::spawn(async move {
+                            match provider.get_block_with_txs(block_hash).await {
+                                Ok(Some(block)) => {
+                                    let mut mempool = mempool.lock().unwrap();
+                                    let evicted = mempool.evict_mined(
+                                        block_number,
+                                        block_hash,
+                                        &block.transactions,
+                                    );
+                                    info!(
+                                        "Evicted {} mined transactions, {} pending",
+                                        evicted,
+                                        mempool.len()
+                                    );
+                                }
+                                Ok(None) => {
+                                    let e = WatcherError::BlockNotFound(block_number.as_u64());
+                                    metrics.record_error(&e);
+                                    warn!("{}", e);
+                                }
+                                Err(e) => {
+                                    let e = WatcherError::rpc(e);
+                                    metrics.record_error(&e);
+                                    warn!("Failed to fetch block {:?}: {}", block_hash, e);
+                                }
+                            }
+                        });
+                    }
+                    Event::Transaction { tx, received } => {
+                        propagation.lock().unwrap().observe(tx.hash, &received);
+
+                        if new_block.number != U64::zero() {
+                            let update = mempool
+                                .lock()
+                                .unwrap()
+                                .insert(*tx.clone(), new_block.number);
+
+                            if let MempoolUpdate::Replaced {
+                                old,
+                                cancellation,
+                                fee_bump,
+                            } = update
+                            {
+                                info!(
+                                    "Tx #{:?} replaced #{:?} (cancellation: {}, fee bump: {})",
+                                    tx.hash, old.hash, cancellation, fee_bump
                                 );
+                                tip_book.lock().unwrap().remove(&old);
+                            }
 
-                                // max_fee_per_gas has to be greater than next block's base fee
-                                if tx.max_fee_per_gas.unwrap_or_default()
-                                    > U256::from(next_base_fee)
-                                {
-                                    match trace_state_diff(
-                                        provider.clone(),
-                                        &tx,
-                                        new_block.number,
-                                        &pools,
-                                        target_address.clone(),
-                                    )
-                                    .await
-                                    {
-                                        Ok(_) => {}
-                                        Err(_) => {}
-                                    }
+                            let fees = TxFees::of(&tx);
+                            if pays_base_fees(&tip_book.lock().unwrap(), &fees, base_fee_jitter) {
+                                // re-gossiped, or already received from another node.
+                                // Only traced transactions are marked, so one that arrived
+                                // before the first header is traced when it comes again.
+                                if !seen.insert(tx.hash) {
+                                    Metrics::incr(&metrics.duplicate_txs, 1);
+                                    continue;
                                 }
+
+                                tip_book.lock().unwrap().insert(&tx, &fees);
+                                in_flight.spawn(tracer.clone().trace(
+                                    *tx,
+                                    fees,
+                                    tip_book.clone(),
+                                    new_block.number,
+                                ));
                             }
                         }
-                    },
-                    Err(_) => {}
+                    }
+                    Event::Reorg { dropped, new } => {
+                        let orphaned: Vec<_> = dropped.iter().map(|block| block.hash).collect();
+                        let dropped: Vec<_> = dropped
+                            .iter()
+                            .map(|block| format!("#{} {:?}", block.number, block.hash))
+                            .collect();
+                        let new: Vec<_> = new
+                            .iter()
+                            .map(|block| format!("#{} {:?}", block.number, block.hash))
+                            .collect();
+                        warn!(
+                            "Reorg: dropped [{}], new [{}]",
+                            dropped.join(", "),
+                            new.join(", ")
+                        );
+
+                        // traces ran against orphaned state
+                        if !in_flight.is_empty() {
+                            info!("Dropping {} orphaned traces", in_flight.len());
+                            in_flight.abort_all();
+                        }
+
+                        let mut mempool = mempool.lock().unwrap();
+                        let restored = mempool.restore(&orphaned);
+                        let cleared = mempool.clear_detections();
+                        info!(
+                            "Restored {} transactions of orphaned blocks, cleared the detections of {}",
+                            restored.len(),
+                            cleared.len()
+                        );
+
+                        // nodes don't announce them again, they are traced on the new tip
+                        retrace.extend(restored);
+                        retrace.extend(cleared);
+                    }
+                    Event::Reconnected { node, subscription } => {
+                        info!(
+                            "{:?} of node {} reconnected, events in between were missed",
+                            subscription, node
+                        );
+
+                        // headers may have been missed, wait for the next one before tracing again,
+                        // unless another node's header stream kept them coming
+                        if subscription == Subscription::NewHeads
+                            && Metrics::get(&metrics.live_header_streams) <= 1
+                        {
+                            new_block = NewBlock::default();
+                        }
+                    }
+                },
+                None => break,
+            }
+        }
+
+        // let the last traces finish, playback ends right after its last event
+        while let Some(joined) = in_flight.join_next().await {
+            check_joined(joined, &metrics);
+        }
+
+        // what is still queued is written before returning
+        if let Some(recorder) = recorder {
+            recorder.close().await;
+        }
+
+        metrics.report();
+        propagation.lock().unwrap().report();
+
+        Ok(())
+    }
+}
+
+// The transaction has to pay at least the next block's base fees
+fn pays_base_fees(tip_book: &TipBook, fees: &TxFees, base_fee_jitter: u64) -> bool {
+    fees.effective_gas_price(with_jitter(tip_book.base_fee(), base_fee_jitter))
+        .is_some()
+        && fees.covers_blob_base_fee(tip_book.blob_base_fee())
+}
+
+// What a trace of a pending transaction works with, cloned into every trace task
+#[derive(Clone)]
+struct Tracer {
+    backend: Arc<dyn TraceBackend>,
+    pools: Arc<DashMap<H160, Pool>>,
+    targets: Arc<Vec<TargetToken>>,
+    mempool: Arc<Mutex<Mempool>>,
+    propagation: Arc<Mutex<Propagation>>,
+    metrics: Arc<Metrics>,
+    semaphore: Arc<Semaphore>,
+    timeout: Duration,
+    target: TraceTarget,
+    bundle_size: usize,
+    output: OutputFormat,
+}
+
+impl Tracer {
+    // Traces `tx` on top of `block_number` and reports the pools it touches,
+    // scored against the other pending transactions in `tip_book`
+    async fn trace(
+        self,
+        tx: Transaction,
+        fees: TxFees,
+        tip_book: Arc<Mutex<TipBook>>,
+        block_number: U64,
+    ) {
+        // waits here while max_in_flight traces are running, the semaphore is never closed
+        let _permit = self.semaphore.acquire_owned().await.unwrap();
+
+        let block = match self.target {
+            TraceTarget::Pending => BlockNumber::Pending,
+            TraceTarget::Latest | TraceTarget::Bundle => BlockNumber::from(block_number),
+        };
+
+        let trace = async {
+            match self.target {
+                TraceTarget::Bundle => {
+                    trace_bundle(
+                        self.backend.as_ref(),
+                        &tx,
+                        block,
+                        &self.pools,
+                        &self.targets,
+                        |touched_pools| {
+                            let base_fee = tip_book.lock().unwrap().base_fee();
+                            self.mempool.lock().unwrap().ahead(
+                                &tx,
+                                touched_pools,
+                                base_fee,
+                                self.bundle_size,
+                            )
+                        },
+                    )
+                    .await
+                }
+                _ => {
+                    trace_state_diff(
+                        self.backend.as_ref(),
+                        &tx,
+                        &[],
+                        block,
+                        &self.pools,
+                        &self.targets,
+                    )
+                    .await
                 }
             }
+        };
+
+        let result = match tokio::time::timeout(self.timeout, trace).await {
+            Ok(result) => result,
+            Err(_) => Err(WatcherError::RpcTimeout(self.timeout)),
+        };
+
+        let result = result.and_then(|mut touched| {
+            // ranked against everything seen until the trace finished
+            let score = tip_book.lock().unwrap().score(&fees, tx.gas);
+
+            if let (Some(score), false) = (&score, touched.is_empty()) {
+                info!(
+                    "Tx #{:?}: expected position {} with tip {} ({} gas ahead)",
+                    tx.hash, score.position, score.effective_tip, score.gas_ahead
+                );
+            }
+
+            let first_seen = match touched.is_empty() {
+                true => Vec::new(),
+                false => self.propagation.lock().unwrap().sightings(&tx.hash),
+            };
+
+            for detection in touched.iter_mut() {
+                detection.score = score.clone();
+                detection.first_seen = first_seen.clone();
+            }
+
+            if !touched.is_empty() {
+                self.mempool.lock().unwrap().record(tx.hash, &touched);
+            }
+
+            self.output
+                .emit(&touched)
+                .map_err(|e| WatcherError::Output(e.to_string()))
         });
+
+        if let Err(e) = result {
+            self.metrics.record_error(&e);
+            warn!("Tx #{:?}: {}", tx.hash, e);
+        }
     }
+}
 
-    while let Some(res) = set.join_next().await {
-        info!("{:?}", res);
+// Traces are aborted on every block, only the ones that panicked are failures
+fn check_joined(joined: Result<(), JoinError>, metrics: &Metrics) {
+    if let Err(e) = joined {
+        if !e.is_cancelled() {
+            let e = WatcherError::TaskFailed(e.to_string());
+            metrics.record_error(&e);
+            warn!("{}", e);
+        }
     }
+}
+
+// Streams headers and pending transactions from every connector into one handler,
+// `provider` is used for tracing and fetching blocks
+pub async fn mempool_watching<M, C>(
+    provider: Arc<M>,
+    connectors: Vec<Arc<C>>,
+    config: WatcherConfig,
+    output: OutputFormat,
+) -> Result<()>
+where
+    M: Middleware + 'static,
+    M::Error: 'static,
+    C: Connector,
+{
+    let watcher = Watcher::new(provider, config.clone()).await?;
+
+    // Step #2: Stream data asynchronously
+    let (event_sender, event_receiver) = event_channel(
+        config.channels.lag_policy,
+        config.channels.event_capacity,
+        watcher.metrics.clone(),
+    );
+
+    let mut set = JoinSet::new();
+
+    // shared by the header streams, so a header is only sent by the first node that has it
+    let chain = Arc::new(Mutex::new(ChainTracker::new(config.chain.depth)));
+
+    for (node, connector) in connectors.iter().enumerate() {
+        // Stream new headers
+        set.spawn(stream_headers(
+            node,
+            connector.clone(),
+            config.rpc.reconnect.clone(),
+            chain.clone(),
+            event_sender.clone(),
+            watcher.metrics.clone(),
+        ));
+
+        // Stream pending transactions
+        set.spawn(stream_pending_txs(
+            node,
+            connector.clone(),
+            config.rpc.reconnect.clone(),
+            config.channels.pending_tx_buffer,
+            event_sender.clone(),
+            watcher.metrics.clone(),
+        ));
+    }
+
+    let recorder = match &config.recording.dir {
+        Some(dir) => Some(
+            Recorder::new(
+                dir,
+                config.recording.max_file_bytes,
+                config.recording.max_files,
+            )?
+            .spawn(config.recording.buffer, watcher.metrics.clone()),
+        ),
+        None => None,
+    };
+
+    // Step #3: Handle the events, the streams run as long as the handler does
+    let nodes = connectors
+        .iter()
+        .map(|connector| connector.name())
+        .collect();
+    watcher.run(event_receiver, nodes, recorder, output).await
+}
+
+// Feeds recorded events through the same handler, `speed` times faster than they arrived
+pub async fn playback<M>(
+    provider: Arc<M>,
+    config: WatcherConfig,
+    files: Vec<PathBuf>,
+    speed: f64,
+    output: OutputFormat,
+) -> Result<()>
+where
+    M: Middleware + 'static,
+    M::Error: 'static,
+{
+    let watcher = Watcher::new(provider, config.clone()).await?;
+
+    // nothing is dropped, playback slows down instead when the handler falls behind
+    let (event_sender, event_receiver) = event_channel(
+        LagPolicy::Backpressure,
+        config.channels.event_capacity,
+        watcher.metrics.clone(),
+    );
+
+    let player = tokio::spawn(play(files, speed, event_sender));
+    watcher
+        .run(event_receiver, Vec::new(), None, output)
+        .await?;
+
+    let played = player.await??;
+    info!("Played {} events", played);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slots::StorageLayout;
+    use cfmms::pool::UniswapV2Pool;
+    use ethers::types::ChangedType;
+
+    fn word(value: u64) -> H256 {
+        H256::from_low_u64_be(value)
+    }
+
+    fn changed(from: u64, to: u64) -> Diff<H256> {
+        Diff::Changed(ChangedType {
+            from: word(from),
+            to: word(to),
+        })
+    }
+
+    fn target() -> TargetToken {
+        TargetToken {
+            address: Address::repeat_byte(0xee),
+            balance_slot: BalanceSlot {
+                index: U256::from(3),
+                layout: StorageLayout::Solidity,
+            },
+        }
+    }
+
+    fn pools(pairs: &[(H160, Address, Address)]) -> DashMap<H160, Pool> {
+        pairs
+            .iter()
+            .map(|(address, token_a, token_b)| {
+                let pool = UniswapV2Pool {
+                    address: *address,
+                    token_a: *token_a,
+                    token_b: *token_b,
+                    ..Default::default()
+                };
+                (*address, Pool::UniswapV2(pool))
+            })
+            .collect()
+    }
+
+    fn account(storage: &[(H256, Diff<H256>)]) -> AccountDiff {
+        AccountDiff {
+            balance: Diff::Same,
+            nonce: Diff::Same,
+            code: Diff::Same,
+            storage: storage.iter().cloned().collect(),
+        }
+    }
+
+    #[test]
+    fn balance_change_of_every_diff() {
+        let cases = [
+            (Diff::Same, None),
+            (Diff::Born(word(5)), Some((0, 5))),
+            (Diff::Died(word(5)), Some((5, 0))),
+            (changed(5, 8), Some((5, 8))),
+            (changed(8, 5), Some((8, 5))),
+        ];
+
+        for (diff, expected) in cases {
+            let expected = expected.map(|(from, to)| (U256::from(from), U256::from(to)));
+            assert_eq!(balance_change(&diff), expected, "{:?}", diff);
+        }
+    }
+
+    #[test]
+    fn swaps_are_detected_in_both_directions() {
+        let cases = [
+            (5, 8, Some((SwapDirection::TargetToToken, 3))),
+            (8, 5, Some((SwapDirection::TokenToTarget, -3))),
+            (5, 5, None),
+        ];
+
+        for (from, to, expected) in cases {
+            let expected = expected.map(|(direction, amount)| (direction, I256::from(amount)));
+            assert_eq!(
+                swap_direction(U256::from(from), U256::from(to)),
+                expected,
+                "{} -> {}",
+                from,
+                to
+            );
+        }
+    }
+
+    #[test]
+    fn amounts_cover_the_whole_balance_range() {
+        assert_eq!(
+            swap_direction(U256::zero(), U256::from(u128::MAX)),
+            Some((SwapDirection::TargetToToken, I256::from(u128::MAX)))
+        );
+        assert_eq!(
+            swap_direction(U256::from(u128::MAX), U256::zero()),
+            Some((SwapDirection::TokenToTarget, -I256::from(u128::MAX)))
+        );
+    }
+
+    #[test]
+    fn pools_are_matched_in_both_directions() {
+        let target = target();
+        let pool = H160::repeat_byte(0x01);
+        let token = Address::repeat_byte(0x02);
+        let pools = pools(&[(pool, target.address, token)]);
+        let slot = target.balance_slot.slot_of(pool);
+
+        let cases = [
+            (changed(100, 150), Some((SwapDirection::TargetToToken, 50))),
+            (changed(150, 100), Some((SwapDirection::TokenToTarget, -50))),
+            // a new pool's first deposit, and a full drain
+            (
+                Diff::Born(word(70)),
+                Some((SwapDirection::TargetToToken, 70)),
+            ),
+            (
+                Diff::Died(word(70)),
+                Some((SwapDirection::TokenToTarget, -70)),
+            ),
+            (changed(100, 100), None),
+            (Diff::Same, None),
+        ];
+
+        for (diff, expected) in cases {
+            let state_diff = BTreeMap::from([
+                (pool, account(&[])),
+                (target.address, account(&[(slot, diff.clone())])),
+            ]);
+
+            let touched = analyze_state_diff(word(1), &state_diff, &pools, &[target]).unwrap();
+            let touched: Vec<_> = touched
+                .iter()
+                .map(|detection| {
+                    assert_eq!(detection.pool, pool);
+                    assert_eq!(detection.token, target.address);
+                    assert_eq!(
+                        detection.amount,
+                        I256::from_raw(detection.balance_after)
+                            - I256::from_raw(detection.balance_before)
+                    );
+                    (detection.direction, detection.amount.as_i64())
+                })
+                .collect();
+            assert_eq!(
+                touched,
+                expected.into_iter().collect::<Vec<_>>(),
+                "{:?}",
+                diff
+            );
+        }
+    }
+
+    #[test]
+    fn pools_without_the_target_token_are_ignored() {
+        let target = target();
+        let pool = H160::repeat_byte(0x01);
+        let pools = pools(&[(pool, Address::repeat_byte(0x02), Address::repeat_byte(0x03))]);
+        // even with the target's storage in the diff
+        let state_diff = BTreeMap::from([
+            (pool, account(&[])),
+            (
+                target.address,
+                account(&[(target.balance_slot.slot_of(pool), changed(1, 2))]),
+            ),
+        ]);
+
+        let touched = analyze_state_diff(word(1), &state_diff, &pools, &[target]).unwrap();
+        assert!(touched.is_empty());
+    }
+
+    #[test]
+    fn touched_pools_need_the_target_storage() {
+        let target = target();
+        let pool = H160::repeat_byte(0x01);
+        let pools = pools(&[(pool, Address::repeat_byte(0x02), target.address)]);
+
+        // the target token is not in the diff at all
+        let state_diff = BTreeMap::from([(pool, account(&[]))]);
+        assert!(matches!(
+            analyze_state_diff(word(1), &state_diff, &pools, &[target]),
+            Err(WatcherError::MissingTargetStorage(address)) if address == target.address
+        ));
+
+        // it is, but not the pool's balance slot
+        let state_diff = BTreeMap::from([
+            (pool, account(&[])),
+            (target.address, account(&[(word(9), changed(1, 2))])),
+        ]);
+        let touched = analyze_state_diff(word(1), &state_diff, &pools, &[target]).unwrap();
+        assert!(touched.is_empty());
+    }
+
+    #[test]
+    fn untouched_pools_are_not_reported() {
+        let target = target();
+        let pool = H160::repeat_byte(0x01);
+        let other = H160::repeat_byte(0x04);
+        let pools = pools(&[
+            (pool, target.address, Address::repeat_byte(0x02)),
+            (other, target.address, Address::repeat_byte(0x03)),
+        ]);
+        // the balance of `other` changed in a transfer, but the pool itself isn't in the diff
+        let state_diff = BTreeMap::from([
+            (pool, account(&[])),
+            (
+                target.address,
+                account(&[
+                    (target.balance_slot.slot_of(pool), changed(1, 2)),
+                    (target.balance_slot.slot_of(other), changed(1, 2)),
+                ]),
+            ),
+        ]);
+
+        let touched = analyze_state_diff(word(1), &state_diff, &pools, &[target]).unwrap();
+        assert_eq!(touched.len(), 1);
+        assert_eq!(touched[0].pool, pool);
+    }
+}
@@ -1,32 +1,491 @@
-use ethers::types::U256;
+use ethers::types::{Transaction, H256, U256};
 use rand::Rng;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+// EIP-1559 parameters
+const ELASTICITY_MULTIPLIER: u64 = 2;
+const BASE_FEE_MAX_CHANGE_DENOMINATOR: u64 = 8;
+
+// EIP-4844 parameters
+const MIN_BLOB_BASE_FEE: u64 = 1;
+const GAS_PER_BLOB: u64 = 131072;
+// EIP-7918, from Osaka on blob gas can't get much cheaper than the execution gas of a blob tx
+const BLOB_BASE_COST: u64 = 8192;
+
+// Blob parameters of a fork, in blobs per block
+struct BlobParams {
+    timestamp: u64,
+    target: u64,
+    max: u64,
+    update_fraction: u64,
+    reserve_price: bool,
+}
+
+// Mainnet blob schedule, latest first. Raised by EIP-7691 in Prague and by the
+// blob-parameter-only forks (EIP-7892) after Osaka.
+const BLOB_SCHEDULE: [BlobParams; 5] = [
+    // BPO2
+    BlobParams {
+        timestamp: 1767747671,
+        target: 14,
+        max: 21,
+        update_fraction: 11684671,
+        reserve_price: true,
+    },
+    // BPO1
+    BlobParams {
+        timestamp: 1765290071,
+        target: 10,
+        max: 15,
+        update_fraction: 8346193,
+        reserve_price: true,
+    },
+    // Osaka
+    BlobParams {
+        timestamp: 1764798551,
+        target: 6,
+        max: 9,
+        update_fraction: 5007716,
+        reserve_price: true,
+    },
+    // Prague
+    BlobParams {
+        timestamp: 1746612311,
+        target: 6,
+        max: 9,
+        update_fraction: 5007716,
+        reserve_price: false,
+    },
+    // Cancun
+    BlobParams {
+        timestamp: 1710338135,
+        target: 3,
+        max: 6,
+        update_fraction: 3338477,
+        reserve_price: false,
+    },
+];
+
+// Exact EIP-1559 base fee of the block after a block with the given gas usage
 pub fn calculate_next_block_base_fee(
     gas_used: U256,
     gas_limit: U256,
     base_fee_per_gas: U256,
 ) -> U256 {
-    let gas_used = gas_used;
+    let target_gas_used = gas_limit / ELASTICITY_MULTIPLIER;
 
-    let mut target_gas_used = gas_limit / 2;
-    target_gas_used = if target_gas_used == U256::zero() {
-        U256::one()
+    // no target to compare against (e.g. a zero gas limit), the base fee can't move
+    if target_gas_used.is_zero() {
+        return base_fee_per_gas;
+    }
+
+    // a block can't use more than its gas limit
+    let gas_used = gas_used.min(gas_limit);
+
+    if gas_used > target_gas_used {
+        // increases by at least 1 wei
+        let delta = base_fee_per_gas.saturating_mul(gas_used - target_gas_used)
+            / target_gas_used
+            / BASE_FEE_MAX_CHANGE_DENOMINATOR;
+        base_fee_per_gas.saturating_add(delta.max(U256::one()))
     } else {
-        target_gas_used
-    };
-
-    let new_base_fee = {
-        if gas_used > target_gas_used {
-            base_fee_per_gas
-                + ((base_fee_per_gas * (gas_used - target_gas_used)) / target_gas_used)
-                    / U256::from(8u64)
-        } else {
-            base_fee_per_gas
-                - ((base_fee_per_gas * (target_gas_used - gas_used)) / target_gas_used)
-                    / U256::from(8u64)
+        let delta = base_fee_per_gas.saturating_mul(target_gas_used - gas_used)
+            / target_gas_used
+            / BASE_FEE_MAX_CHANGE_DENOMINATOR;
+        base_fee_per_gas.saturating_sub(delta)
+    }
+}
+
+// Base fee `blocks` blocks ahead, assuming every block in between uses `gas_used`
+pub fn project_base_fee(
+    gas_used: U256,
+    gas_limit: U256,
+    base_fee_per_gas: U256,
+    blocks: usize,
+) -> U256 {
+    (0..blocks).fold(base_fee_per_gas, |base_fee, _| {
+        calculate_next_block_base_fee(gas_used, gas_limit, base_fee)
+    })
+}
+
+// Blob parameters of the block at `timestamp`, blocks before Cancun get Cancun's
+fn blob_params(timestamp: U256) -> &'static BlobParams {
+    BLOB_SCHEDULE
+        .iter()
+        .find(|params| timestamp >= U256::from(params.timestamp))
+        .unwrap_or(&BLOB_SCHEDULE[BLOB_SCHEDULE.len() - 1])
+}
+
+// Excess blob gas of the block at `timestamp`, after a block with the given blob gas usage
+// and base fee
+pub fn calculate_next_block_excess_blob_gas(
+    excess_blob_gas: U256,
+    blob_gas_used: U256,
+    base_fee_per_gas: U256,
+    timestamp: U256,
+) -> U256 {
+    let params = blob_params(timestamp);
+    let target_blob_gas = U256::from(params.target * GAS_PER_BLOB);
+
+    let total = excess_blob_gas.saturating_add(blob_gas_used);
+    if total < target_blob_gas {
+        return U256::zero();
+    }
+
+    // below the reserve price the excess only grows, by what the block used above the target
+    // relative to the max (EIP-7918)
+    let blob_base_fee = calculate_blob_base_fee(excess_blob_gas, timestamp);
+    if params.reserve_price
+        && base_fee_per_gas.saturating_mul(U256::from(BLOB_BASE_COST))
+            > blob_base_fee.saturating_mul(U256::from(GAS_PER_BLOB))
+    {
+        return excess_blob_gas.saturating_add(
+            blob_gas_used.saturating_mul(U256::from(params.max - params.target))
+                / U256::from(params.max),
+        );
+    }
+
+    total - target_blob_gas
+}
+
+// Blob base fee of the block at `timestamp` with the given excess blob gas
+pub fn calculate_blob_base_fee(excess_blob_gas: U256, timestamp: U256) -> U256 {
+    fake_exponential(
+        U256::from(MIN_BLOB_BASE_FEE),
+        excess_blob_gas,
+        U256::from(blob_params(timestamp).update_fraction),
+    )
+}
+
+// factor * e ** (numerator / denominator), approximated with integers as specified in EIP-4844
+fn fake_exponential(factor: U256, numerator: U256, denominator: U256) -> U256 {
+    let mut i = U256::one();
+    let mut output = U256::zero();
+    let mut accum = factor.saturating_mul(denominator);
+
+    while !accum.is_zero() {
+        output = output.saturating_add(accum);
+        accum = accum.saturating_mul(numerator) / denominator.saturating_mul(i);
+        i += U256::one();
+    }
+
+    output / denominator
+}
+
+// Microseconds since the unix epoch, the timestamps written to recordings and detections
+pub fn unix_micros(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+// Adds a random 0..=max_jitter wei on top of a predicted base fee
+pub fn with_jitter(base_fee: U256, max_jitter: u64) -> U256 {
+    if max_jitter == 0 {
+        return base_fee;
+    }
+    base_fee.saturating_add(U256::from(rand::thread_rng().gen_range(0..=max_jitter)))
+}
+
+// Fee caps of a transaction, whatever its type
+#[derive(Debug, Clone, Default)]
+pub struct TxFees {
+    // gas_price for legacy (type 0) and EIP-2930 (type 1) transactions
+    pub max_fee_per_gas: U256,
+    // None for legacy and EIP-2930 transactions, everything above the base fee goes to the builder
+    pub max_priority_fee_per_gas: Option<U256>,
+    // EIP-4844 (type 3) only, ethers keeps these fields in `other`
+    pub max_fee_per_blob_gas: Option<U256>,
+    pub blob_versioned_hashes: Vec<H256>,
+}
+
+impl TxFees {
+    pub fn of(tx: &Transaction) -> Self {
+        let (max_fee_per_gas, max_priority_fee_per_gas) = match tx.max_fee_per_gas {
+            Some(max_fee_per_gas) => (
+                max_fee_per_gas,
+                Some(tx.max_priority_fee_per_gas.unwrap_or_default()),
+            ),
+            None => (tx.gas_price.unwrap_or_default(), None),
+        };
+
+        Self {
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+            max_fee_per_blob_gas: tx
+                .other
+                .get_deserialized("maxFeePerBlobGas")
+                .and_then(|value| value.ok()),
+            blob_versioned_hashes: tx
+                .other
+                .get_deserialized("blobVersionedHashes")
+                .and_then(|value| value.ok())
+                .unwrap_or_default(),
         }
-    };
+    }
+
+    // Price per gas the transaction pays in a block with `base_fee`, None if it can't be included
+    pub fn effective_gas_price(&self, base_fee: U256) -> Option<U256> {
+        self.priority_fee(base_fee).map(|tip| base_fee + tip)
+    }
+
+    // Blob transactions have to cover the blob base fee as well, None before Cancun
+    pub fn covers_blob_base_fee(&self, blob_base_fee: Option<U256>) -> bool {
+        match (self.max_fee_per_blob_gas, blob_base_fee) {
+            (Some(max_fee_per_blob_gas), Some(blob_base_fee)) => {
+                max_fee_per_blob_gas >= blob_base_fee
+            }
+            _ => true,
+        }
+    }
+
+    // Tip per gas the block builder receives in a block with `base_fee`
+    pub fn priority_fee(&self, base_fee: U256) -> Option<U256> {
+        let headroom = self.max_fee_per_gas.checked_sub(base_fee)?;
+        Some(match self.max_priority_fee_per_gas {
+            Some(max_priority_fee_per_gas) => max_priority_fee_per_gas.min(headroom),
+            None => headroom,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAS_LIMIT: u64 = 30_000_000;
+
+    // timestamps of the first block of each fork
+    const CANCUN: u64 = 1710338135;
+    const PRAGUE: u64 = 1746612311;
+    const OSAKA: u64 = 1764798551;
+    const BPO1: u64 = 1765290071;
+    const BPO2: u64 = 1767747671;
+
+    fn gwei(n: f64) -> U256 {
+        U256::from((n * 1e9) as u64)
+    }
+
+    fn next(gas_used: u64, gas_limit: u64, base_fee: U256) -> U256 {
+        calculate_next_block_base_fee(U256::from(gas_used), U256::from(gas_limit), base_fee)
+    }
+
+    #[test]
+    fn base_fee_is_unchanged_at_target() {
+        assert_eq!(next(GAS_LIMIT / 2, GAS_LIMIT, gwei(100.0)), gwei(100.0));
+    }
+
+    #[test]
+    fn base_fee_rises_above_target() {
+        // a full block raises it by 1/8
+        assert_eq!(next(GAS_LIMIT, GAS_LIMIT, gwei(100.0)), gwei(112.5));
+        assert_eq!(
+            next(GAS_LIMIT * 3 / 4, GAS_LIMIT, gwei(100.0)),
+            gwei(106.25)
+        );
+    }
+
+    #[test]
+    fn base_fee_rises_by_at_least_one_wei() {
+        let base_fee = U256::from(7);
+        assert_eq!(next(GAS_LIMIT / 2 + 1, GAS_LIMIT, base_fee), base_fee + 1);
+    }
+
+    #[test]
+    fn base_fee_falls_below_target() {
+        // an empty block lowers it by 1/8
+        assert_eq!(next(0, GAS_LIMIT, gwei(100.0)), gwei(87.5));
+        assert_eq!(next(GAS_LIMIT / 4, GAS_LIMIT, gwei(100.0)), gwei(93.75));
+        // the decrease rounds down to nothing
+        assert_eq!(next(0, GAS_LIMIT, U256::from(7)), U256::from(7));
+    }
+
+    #[test]
+    fn base_fee_is_unchanged_without_gas_limit() {
+        assert_eq!(next(GAS_LIMIT, 0, gwei(100.0)), gwei(100.0));
+    }
+
+    #[test]
+    fn gas_used_is_capped_at_the_gas_limit() {
+        assert_eq!(
+            next(GAS_LIMIT * 2, GAS_LIMIT, gwei(100.0)),
+            next(GAS_LIMIT, GAS_LIMIT, gwei(100.0))
+        );
+    }
+
+    #[test]
+    fn projection_compounds_over_blocks() {
+        let project = |gas_used: u64, blocks| {
+            project_base_fee(
+                U256::from(gas_used),
+                U256::from(GAS_LIMIT),
+                gwei(100.0),
+                blocks,
+            )
+        };
+
+        assert_eq!(project(GAS_LIMIT, 0), gwei(100.0));
+        assert_eq!(project(GAS_LIMIT, 1), gwei(112.5));
+        assert_eq!(project(GAS_LIMIT, 2), gwei(126.5625));
+        assert_eq!(project(0, 2), gwei(76.5625));
+        assert_eq!(project(GAS_LIMIT / 2, 10), gwei(100.0));
+    }
+
+    #[test]
+    fn fake_exponential_matches_the_eip_vectors() {
+        let exp = |factor: u64, numerator: u64, denominator: u64| {
+            fake_exponential(
+                U256::from(factor),
+                U256::from(numerator),
+                U256::from(denominator),
+            )
+            .as_u64()
+        };
+
+        assert_eq!(exp(1, 0, 1), 1);
+        assert_eq!(exp(38493, 0, 1000), 38493);
+        assert_eq!(exp(1, 2, 1), 6);
+        assert_eq!(exp(1, 3, 1), 16);
+        assert_eq!(exp(10, 8, 2), 542);
+        assert_eq!(exp(1, 50000000, 2225652), 5709098764);
+    }
+
+    #[test]
+    fn blob_base_fee_follows_the_excess_blob_gas() {
+        let blob_base_fee = |excess: u64, timestamp: u64| {
+            calculate_blob_base_fee(U256::from(excess), U256::from(timestamp))
+        };
+
+        assert_eq!(blob_base_fee(0, CANCUN), U256::one());
+        // e ** 1
+        assert_eq!(blob_base_fee(3338477, CANCUN), U256::from(2));
+        // the same excess moves the fee less with every raise of the target
+        let excess = 3338477 * 10;
+        assert!(blob_base_fee(excess, PRAGUE) < blob_base_fee(excess, CANCUN));
+        assert_eq!(blob_base_fee(excess, OSAKA), blob_base_fee(excess, PRAGUE));
+        assert!(blob_base_fee(excess, BPO1) < blob_base_fee(excess, OSAKA));
+        assert!(blob_base_fee(excess, BPO2) < blob_base_fee(excess, BPO1));
+    }
+
+    #[test]
+    fn excess_blob_gas_accumulates_above_target() {
+        let excess = |excess: u64, blobs: u64, timestamp: u64| {
+            calculate_next_block_excess_blob_gas(
+                U256::from(excess),
+                U256::from(blobs * GAS_PER_BLOB),
+                U256::zero(),
+                U256::from(timestamp),
+            )
+            .as_u64()
+        };
+
+        assert_eq!(excess(0, 6, CANCUN), 393216);
+        assert_eq!(excess(393216, 0, CANCUN), 0);
+        assert_eq!(excess(100, 1, CANCUN), 0);
+        assert_eq!(excess(0, 6, PRAGUE), 0);
+        assert_eq!(excess(0, 9, PRAGUE), 393216);
+        assert_eq!(excess(0, 9, OSAKA), 393216);
+        assert_eq!(excess(0, 15, BPO1), 655360);
+        assert_eq!(excess(0, 21, BPO2), 917504);
+        // blocks before Cancun get Cancun's target
+        assert_eq!(excess(0, 6, CANCUN - 12), 393216);
+    }
+
+    #[test]
+    fn excess_blob_gas_grows_below_the_reserve_price_from_osaka() {
+        let excess = |blobs: u64, base_fee: U256, timestamp: u64| {
+            calculate_next_block_excess_blob_gas(
+                U256::zero(),
+                U256::from(blobs * GAS_PER_BLOB),
+                base_fee,
+                U256::from(timestamp),
+            )
+            .as_u64()
+        };
+
+        // a 1 wei blob base fee is below the reserve price of a 1 gwei base fee
+        assert_eq!(excess(6, gwei(1.0), PRAGUE), 0);
+        assert_eq!(excess(6, gwei(1.0), OSAKA), 262144);
+        assert_eq!(excess(14, gwei(1.0), BPO2), 611669);
+        // still nothing below the target
+        assert_eq!(excess(0, gwei(1.0), OSAKA), 0);
+        // a negligible base fee doesn't hold the blob base fee up
+        assert_eq!(excess(6, U256::one(), OSAKA), 0);
+    }
+
+    fn legacy(gas_price: U256) -> Transaction {
+        Transaction {
+            gas_price: Some(gas_price),
+            ..Default::default()
+        }
+    }
+
+    fn eip1559(max_fee_per_gas: U256, max_priority_fee_per_gas: U256) -> Transaction {
+        Transaction {
+            transaction_type: Some(2.into()),
+            // nodes fill it in with the effective price of mined transactions
+            gas_price: Some(max_fee_per_gas),
+            max_fee_per_gas: Some(max_fee_per_gas),
+            max_priority_fee_per_gas: Some(max_priority_fee_per_gas),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn legacy_and_eip2930_transactions_pay_their_gas_price() {
+        let mut eip2930 = legacy(gwei(30.0));
+        eip2930.transaction_type = Some(1.into());
+        eip2930.access_list = Some(Default::default());
+
+        for tx in [legacy(gwei(30.0)), eip2930] {
+            let fees = TxFees::of(&tx);
+
+            assert_eq!(fees.max_fee_per_gas, gwei(30.0));
+            assert_eq!(fees.max_priority_fee_per_gas, None);
+            // everything above the base fee is tip
+            assert_eq!(fees.priority_fee(gwei(20.0)), Some(gwei(10.0)));
+            assert_eq!(fees.effective_gas_price(gwei(20.0)), Some(gwei(30.0)));
+            assert_eq!(fees.priority_fee(gwei(30.0)), Some(U256::zero()));
+            assert_eq!(fees.effective_gas_price(gwei(31.0)), None);
+            assert!(fees.covers_blob_base_fee(Some(gwei(1.0))));
+        }
+    }
+
+    #[test]
+    fn eip1559_transactions_pay_at_most_their_priority_fee() {
+        let fees = TxFees::of(&eip1559(gwei(40.0), gwei(2.0)));
+
+        assert_eq!(fees.max_fee_per_gas, gwei(40.0));
+        assert_eq!(fees.max_priority_fee_per_gas, Some(gwei(2.0)));
+        assert_eq!(fees.priority_fee(gwei(20.0)), Some(gwei(2.0)));
+        assert_eq!(fees.effective_gas_price(gwei(20.0)), Some(gwei(22.0)));
+        // capped by the max fee close to it
+        assert_eq!(fees.priority_fee(gwei(39.0)), Some(gwei(1.0)));
+        assert_eq!(fees.effective_gas_price(gwei(39.0)), Some(gwei(40.0)));
+        assert_eq!(fees.effective_gas_price(gwei(41.0)), None);
+        assert_eq!(fees.max_fee_per_blob_gas, None);
+    }
+
+    #[test]
+    fn blob_transactions_have_to_cover_the_blob_base_fee() {
+        let mut tx = eip1559(gwei(40.0), gwei(2.0));
+        tx.transaction_type = Some(3.into());
+        tx.other.insert(
+            String::from("maxFeePerBlobGas"),
+            serde_json::json!(format!("{:#x}", 100)),
+        );
+        tx.other.insert(
+            String::from("blobVersionedHashes"),
+            serde_json::json!([H256::repeat_byte(1)]),
+        );
+        let fees = TxFees::of(&tx);
 
-    let seed = rand::thread_rng().gen_range(0..9);
-    new_base_fee + seed
+        assert_eq!(fees.max_fee_per_blob_gas, Some(U256::from(100)));
+        assert_eq!(fees.blob_versioned_hashes, vec![H256::repeat_byte(1)]);
+        assert_eq!(fees.effective_gas_price(gwei(20.0)), Some(gwei(22.0)));
+        assert!(fees.covers_blob_base_fee(Some(U256::from(100))));
+        assert!(!fees.covers_blob_base_fee(Some(U256::from(101))));
+        // before Cancun there is nothing to cover
+        assert!(fees.covers_blob_base_fee(None));
+    }
 }